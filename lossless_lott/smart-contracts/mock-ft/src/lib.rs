@@ -0,0 +1,55 @@
+//! A bare NEP-141 token standing in for wNEAR or a liquid staking token when
+//! the lottery's `ft_on_transfer` is exercised against a locally deployed
+//! contract. Anyone can mint to themselves.
+use near_contract_standards::fungible_token::metadata::{
+    FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC,
+};
+use near_contract_standards::fungible_token::FungibleToken;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::{env, near_bindgen, AccountId, PanicOnDefault, PromiseOrValue};
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct MockToken {
+    token: FungibleToken,
+    symbol: String,
+}
+
+#[near_bindgen]
+impl MockToken {
+    #[init]
+    pub fn new(symbol: String) -> Self {
+        Self {
+            token: FungibleToken::new(b"t"),
+            symbol,
+        }
+    }
+
+    /// Registers the caller if needed and mints `amount` to them.
+    pub fn mint(&mut self, amount: U128) {
+        let account_id = env::predecessor_account_id();
+        if !self.token.accounts.contains_key(&account_id) {
+            self.token.internal_register_account(&account_id);
+        }
+        self.token.internal_deposit(&account_id, amount.0);
+    }
+}
+
+near_contract_standards::impl_fungible_token_core!(MockToken, token);
+near_contract_standards::impl_fungible_token_storage!(MockToken, token);
+
+#[near_bindgen]
+impl FungibleTokenMetadataProvider for MockToken {
+    fn ft_metadata(&self) -> FungibleTokenMetadata {
+        FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: self.symbol.clone(),
+            symbol: self.symbol.clone(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 24,
+        }
+    }
+}
@@ -0,0 +1,49 @@
+//! Re-derives a round's winner from an exported draw receipt.
+//!
+//! Usage: `draw-replay <receipt.json> <events.json>`, where the receipt is
+//! the output of `get_draw_receipt` and the events are the data of every
+//! `twab_update` event the contract logged up to the draw, joined into one
+//! JSON array in the order they were logged. The events are checked against
+//! the hash in the receipt.
+use std::{env, fs, process};
+
+use near_sdk::serde::de::DeserializeOwned;
+use near_sdk::serde_json;
+use smart_contracts::receipt::replay;
+use smart_contracts::{DrawReceipt, TwabUpdate};
+
+fn read_json<T: DeserializeOwned>(path: &str) -> T {
+    let contents = fs::read_to_string(path).unwrap_or_else(|err| {
+        eprintln!("Cannot read {}: {}", path, err);
+        process::exit(2);
+    });
+    serde_json::from_str(&contents).unwrap_or_else(|err| {
+        eprintln!("Cannot parse {}: {}", path, err);
+        process::exit(2);
+    })
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: {} <receipt.json> <events.json>", args[0]);
+        process::exit(2);
+    }
+    let receipt: DrawReceipt = read_json(&args[1]);
+    let updates: Vec<TwabUpdate> = read_json(&args[2]);
+
+    match replay(&receipt, &updates) {
+        Ok(_) => {
+            for winner in &receipt.winners {
+                println!(
+                    "Round {}: ticket {} belongs to {}, as recorded",
+                    receipt.round_id, winner.ticket.0, winner.account_id
+                );
+            }
+        }
+        Err(err) => {
+            eprintln!("Round {}: {}", receipt.round_id, err);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,494 @@
+//! The liquidity buffer. Player deposits are staked like investments, except
+//! for a slice of all principal the contract keeps unstaked so that
+//! withdrawals can be paid at once. Deposits top the buffer up to its target
+//! first; the rest is staked at the next `sync_stake`. A plain withdrawal
+//! is paid for free from whatever the buffer holds above its target, and
+//! the rest joins the withdrawal queue and waits out the pool's delay like
+//! an investor's. `instant_withdraw` may dip below the target for a fee,
+//! which goes to the prize pool or to investors.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
+use near_sdk::{env, near_bindgen, AccountId, Balance, Promise};
+
+use crate::events;
+use crate::prize::BASIS_POINTS;
+use crate::vault::mul_div;
+use crate::{is_promise_success, Contract, ContractExt, Ledger, GAS_FOR_ON_WITHDRAW_TRANSFER};
+
+/// Share of principal kept liquid unless the owner says otherwise.
+pub const DEFAULT_LIQUIDITY_BUFFER_BPS: u32 = 1_000;
+
+/// Fee charged on instant withdrawals unless the owner says otherwise.
+pub const DEFAULT_INSTANT_WITHDRAW_FEE_BPS: u32 = 50;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LiquidityBuffer {
+    pub balance: U128,
+    pub target: U128,
+    pub target_bps: u32,
+}
+
+/// Where instant withdrawal fees go.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum FeeRecipient {
+    /// Counted as yield at the next draw.
+    PrizePool,
+    /// Added to the investor vault, raising the share price.
+    Investors,
+}
+
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub struct InstantWithdrawFee {
+    pub fee_bps: u32,
+    pub recipient: FeeRecipient,
+}
+
+impl Default for InstantWithdrawFee {
+    fn default() -> Self {
+        Self {
+            fee_bps: DEFAULT_INSTANT_WITHDRAW_FEE_BPS,
+            recipient: FeeRecipient::PrizePool,
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Pays `amount` of the caller's player balance from the liquidity
+    /// buffer at once, less the instant withdrawal fee, and returns what was
+    /// sent. If the buffer cannot cover it the whole amount joins the
+    /// withdrawal queue instead, free of charge, and nothing is sent.
+    pub fn instant_withdraw(&mut self, amount: U128) -> U128 {
+        let account_id = env::predecessor_account_id();
+        let amount = amount.0;
+        assert!(amount > 0, "Withdrawal amount must be greater than zero");
+        self.debit(&account_id, Ledger::Player, amount);
+
+        let fee = mul_div(
+            amount,
+            self.instant_withdraw_fee.fee_bps as u128,
+            BASIS_POINTS as u128,
+            true,
+        );
+        let payout = amount - fee;
+        // The fee is given up out of staked principal, so the buffer only
+        // pays out what the player receives. It is collected once the
+        // transfer has gone through.
+        if payout > self.liquid_buffer || fee > self.total_stake {
+            env::log_str(&format!(
+                "Liquidity buffer cannot cover {}, queueing the withdrawal",
+                amount
+            ));
+            self.queue_principal(&account_id, amount);
+            return U128(0);
+        }
+        self.liquid_buffer -= payout;
+        Promise::new(account_id.clone()).transfer(payout).then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_ON_WITHDRAW_TRANSFER)
+                .on_instant_withdraw_transfer(account_id, U128(payout), U128(fee)),
+        );
+        U128(payout)
+    }
+
+    /// Takes the fee once the payout has gone through. If it did not, the
+    /// player gets back everything that was debited, fee included.
+    #[private]
+    pub fn on_instant_withdraw_transfer(
+        &mut self,
+        account_id: AccountId,
+        payout: U128,
+        fee: U128,
+    ) -> bool {
+        if is_promise_success() {
+            self.collect_instant_fee(&account_id, fee.0);
+            return true;
+        }
+        env::log_str(&format!(
+            "Transfer of {} to {} failed, restoring balance",
+            payout.0, account_id
+        ));
+        self.credit(&account_id, Ledger::Player, payout.0 + fee.0);
+        self.liquid_buffer += payout.0;
+        false
+    }
+
+    pub fn set_instant_withdraw_fee(&mut self, fee: InstantWithdrawFee) {
+        self.assert_owner();
+        assert!(
+            fee.fee_bps <= BASIS_POINTS,
+            "Instant withdrawal fee can be at most {} basis points",
+            BASIS_POINTS
+        );
+        self.instant_withdraw_fee = fee;
+    }
+
+    pub fn get_instant_withdraw_fee(&self) -> InstantWithdrawFee {
+        self.instant_withdraw_fee
+    }
+
+    /// Sets the share of principal to keep unstaked. Takes effect as
+    /// deposits come in; nothing is unstaked to fill a larger buffer.
+    pub fn set_liquidity_buffer_bps(&mut self, bps: u32) {
+        self.assert_owner();
+        assert!(
+            bps <= BASIS_POINTS,
+            "Liquidity buffer can be at most {} basis points",
+            BASIS_POINTS
+        );
+        self.liquidity_buffer_bps = bps;
+    }
+
+    pub fn get_liquidity_buffer(&self) -> LiquidityBuffer {
+        LiquidityBuffer {
+            balance: U128(self.liquid_buffer),
+            target: U128(self.buffer_target()),
+            target_bps: self.liquidity_buffer_bps,
+        }
+    }
+}
+
+impl Contract {
+    /// The buffer's share of all principal, staked or not.
+    pub(crate) fn buffer_target(&self) -> Balance {
+        mul_div(
+            self.total_stake + self.liquid_buffer,
+            self.liquidity_buffer_bps as u128,
+            BASIS_POINTS as u128,
+            false,
+        )
+    }
+
+    /// Adds a NEAR deposit to the buffer and leaves whatever takes it past
+    /// the target for the next `sync_stake`.
+    pub(crate) fn deposit_principal(&mut self, amount: Balance) {
+        self.liquid_buffer += amount;
+        let excess = self.liquid_buffer.saturating_sub(self.buffer_target());
+        if excess > 0 {
+            self.liquid_buffer -= excess;
+            self.total_stake += excess;
+            self.pending_stake += excess;
+        }
+    }
+
+    /// Pays a player's withdrawal from the buffer's surplus over its target
+    /// and queues the rest.
+    pub(crate) fn withdraw_principal(&mut self, account_id: &AccountId, amount: Balance) {
+        let surplus = self.liquid_buffer.saturating_sub(self.buffer_target());
+        let liquid = std::cmp::min(amount, surplus);
+        if liquid > 0 {
+            self.liquid_buffer -= liquid;
+            self.transfer_to(account_id.clone(), Ledger::Player, liquid);
+        }
+        if amount > liquid {
+            self.queue_principal(account_id, amount - liquid);
+        }
+    }
+
+    /// Queues a player's withdrawal to be unstaked. Any part the pool does
+    /// not hold, as when the buffer carries most of the principal, is paid
+    /// from the buffer straight away.
+    pub(crate) fn queue_principal(&mut self, account_id: &AccountId, amount: Balance) {
+        let staked = std::cmp::min(amount, self.total_stake);
+        if staked > 0 {
+            self.total_stake -= staked;
+            self.queue_withdrawal(account_id, Ledger::Player, staked);
+        }
+        let liquid = amount - staked;
+        if liquid > 0 {
+            self.liquid_buffer -= liquid;
+            self.transfer_to(account_id.clone(), Ledger::Player, liquid);
+        }
+    }
+
+    /// Stops owing `fee` as principal. It stays staked, where the next yield
+    /// measurement counts it towards the prize, or is added to the vault.
+    fn collect_instant_fee(&mut self, account_id: &AccountId, fee: Balance) {
+        if fee == 0 {
+            return;
+        }
+        self.total_stake -= fee;
+        // An empty vault has nobody to pay, so the fee goes to the prize.
+        let recipient = match self.instant_withdraw_fee.recipient {
+            FeeRecipient::Investors if self.total_shares == 0 => FeeRecipient::PrizePool,
+            recipient => recipient,
+        };
+        if recipient == FeeRecipient::Investors {
+            self.accrue_investor_yield(fee);
+        }
+        events::emit(
+            "instant_withdraw_fee",
+            json!({
+                "account_id": account_id,
+                "amount": U128(fee),
+                "recipient": recipient,
+            }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::UNSTAKE_DELAY_EPOCHS;
+    use near_sdk::PromiseResult;
+
+    fn play(contract: &mut Contract, name: &str, amount: Balance) {
+        set_context(name, amount);
+        contract.play(U128(amount));
+    }
+
+    #[test]
+    fn test_player_deposit_is_staked_beyond_buffer() {
+        let mut contract = setup();
+        play(&mut contract, "bob.testnet", 10 * MIN_DEPOSIT);
+
+        assert_eq!(contract.liquid_buffer, MIN_DEPOSIT);
+        assert_eq!(contract.total_stake, 9 * MIN_DEPOSIT);
+        assert_eq!(contract.pending_stake, 9 * MIN_DEPOSIT);
+    }
+
+    #[test]
+    fn test_deposits_refill_buffer_first() {
+        let mut contract = setup();
+        set_context("alice.testnet", 100 * MIN_DEPOSIT);
+        contract.invest(U128(100 * MIN_DEPOSIT));
+        play(&mut contract, "bob.testnet", 5 * MIN_DEPOSIT);
+
+        // The target is 10% of 105; all of the deposit stays liquid.
+        assert_eq!(contract.liquid_buffer, 5 * MIN_DEPOSIT);
+        assert_eq!(contract.total_stake, 100 * MIN_DEPOSIT);
+        assert_eq!(
+            contract.get_liquidity_buffer().target,
+            U128(21 * MIN_DEPOSIT / 2)
+        );
+    }
+
+    /// The default fee on `amount`.
+    fn fee(amount: Balance) -> Balance {
+        amount * DEFAULT_INSTANT_WITHDRAW_FEE_BPS as u128 / BASIS_POINTS as u128
+    }
+
+    #[test]
+    fn test_instant_withdrawal_is_paid_from_buffer() {
+        let mut contract = setup();
+        play(&mut contract, "bob.testnet", 10 * MIN_DEPOSIT);
+        set_context("bob.testnet", 0);
+        let paid = contract.instant_withdraw(U128(MIN_DEPOSIT));
+
+        assert_eq!(paid, U128(MIN_DEPOSIT - fee(MIN_DEPOSIT)));
+        assert_eq!(contract.liquid_buffer, fee(MIN_DEPOSIT));
+        // The fee is only taken once the transfer succeeds.
+        assert_eq!(contract.total_stake, 9 * MIN_DEPOSIT);
+        assert_eq!(
+            contract.players.get(&account("bob.testnet")),
+            Some(9 * MIN_DEPOSIT)
+        );
+        assert!(contract
+            .get_pending_withdrawals(account("bob.testnet"))
+            .is_empty());
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        assert_eq!(receipts[0].receiver_id, account("bob.testnet"));
+
+        set_callback_context(vec![PromiseResult::Successful(vec![])]);
+        assert!(contract.on_instant_withdraw_transfer(
+            account("bob.testnet"),
+            paid,
+            U128(fee(MIN_DEPOSIT))
+        ));
+        assert_eq!(contract.total_stake, 9 * MIN_DEPOSIT - fee(MIN_DEPOSIT));
+    }
+
+    #[test]
+    fn test_instant_fee_counts_towards_prize() {
+        let mut contract = setup();
+        play(&mut contract, "bob.testnet", 10 * MIN_DEPOSIT);
+        contract.sync_stake();
+        set_context("bob.testnet", 0);
+        let paid = contract.instant_withdraw(U128(MIN_DEPOSIT));
+        set_callback_context(vec![PromiseResult::Successful(vec![])]);
+        contract.on_instant_withdraw_transfer(account("bob.testnet"), paid, U128(fee(MIN_DEPOSIT)));
+
+        // The pool still holds the fee but no longer owes it.
+        assert_eq!(
+            contract.principal_in_pool(),
+            U128(9 * MIN_DEPOSIT - fee(MIN_DEPOSIT))
+        );
+        assert!(near_sdk::test_utils::get_logs()
+            .iter()
+            .any(|log| log.contains("\"event\":\"instant_withdraw_fee\"")));
+    }
+
+    #[test]
+    fn test_instant_fee_can_go_to_investors() {
+        let mut contract = setup();
+        set_context("owner.testnet", 0);
+        contract.set_instant_withdraw_fee(InstantWithdrawFee {
+            fee_bps: 100,
+            recipient: FeeRecipient::Investors,
+        });
+        set_context("alice.testnet", 100 * MIN_DEPOSIT);
+        contract.invest(U128(100 * MIN_DEPOSIT));
+        play(&mut contract, "bob.testnet", 10 * MIN_DEPOSIT);
+        set_context("bob.testnet", 0);
+        let paid = contract.instant_withdraw(U128(10 * MIN_DEPOSIT));
+        set_callback_context(vec![PromiseResult::Successful(vec![])]);
+        contract.on_instant_withdraw_transfer(account("bob.testnet"), paid, U128(MIN_DEPOSIT / 10));
+
+        assert_eq!(paid, U128(10 * MIN_DEPOSIT - MIN_DEPOSIT / 10));
+        assert_eq!(
+            contract.total_assets(),
+            U128(100 * MIN_DEPOSIT + MIN_DEPOSIT / 10)
+        );
+        assert_eq!(contract.liquid_buffer, MIN_DEPOSIT / 10);
+        assert_eq!(
+            contract.total_stake + contract.liquid_buffer,
+            contract.vault_assets
+        );
+    }
+
+    #[test]
+    fn test_instant_fee_goes_to_prize_without_investors() {
+        let mut contract = setup();
+        set_context("owner.testnet", 0);
+        contract.set_instant_withdraw_fee(InstantWithdrawFee {
+            fee_bps: 100,
+            recipient: FeeRecipient::Investors,
+        });
+        play(&mut contract, "bob.testnet", 10 * MIN_DEPOSIT);
+        set_context("bob.testnet", 0);
+        let paid = contract.instant_withdraw(U128(MIN_DEPOSIT));
+        set_callback_context(vec![PromiseResult::Successful(vec![])]);
+        contract.on_instant_withdraw_transfer(
+            account("bob.testnet"),
+            paid,
+            U128(MIN_DEPOSIT / 100),
+        );
+        assert_eq!(contract.total_assets(), U128(0));
+
+        set_context("alice.testnet", MIN_DEPOSIT);
+        contract.invest(U128(MIN_DEPOSIT));
+        assert_eq!(contract.get_total_shares(), U128(MIN_DEPOSIT));
+    }
+
+    #[test]
+    fn test_instant_withdraw_falls_back_to_queue() {
+        let mut contract = setup();
+        play(&mut contract, "bob.testnet", 10 * MIN_DEPOSIT);
+        set_context_at("bob.testnet", 0, 0, 3);
+        assert_eq!(contract.instant_withdraw(U128(5 * MIN_DEPOSIT)), U128(0));
+
+        assert_eq!(contract.liquid_buffer, MIN_DEPOSIT);
+        assert_eq!(contract.total_stake, 4 * MIN_DEPOSIT);
+        let pending = contract.get_pending_withdrawals(account("bob.testnet"));
+        assert_eq!(pending[0].amount, U128(5 * MIN_DEPOSIT));
+        assert_eq!(pending[0].available_epoch, 3 + UNSTAKE_DELAY_EPOCHS);
+    }
+
+    #[test]
+    fn test_withdraw_leaves_buffer_at_target() {
+        let mut contract = setup();
+        play(&mut contract, "bob.testnet", 10 * MIN_DEPOSIT);
+        set_context_at("bob.testnet", 0, 0, 3);
+        contract.withdraw(Ledger::Player, U128(MIN_DEPOSIT));
+
+        assert_eq!(contract.liquid_buffer, MIN_DEPOSIT);
+        assert_eq!(contract.total_stake, 8 * MIN_DEPOSIT);
+        assert_eq!(contract.total_unstaking, MIN_DEPOSIT);
+        assert_eq!(contract.get_unstake_queue(), vec![3]);
+        assert!(near_sdk::test_utils::get_created_receipts().is_empty());
+    }
+
+    #[test]
+    fn test_withdraw_pays_from_buffer_surplus() {
+        let mut contract = setup();
+        play(&mut contract, "bob.testnet", 10 * MIN_DEPOSIT);
+        set_context("owner.testnet", 0);
+        contract.set_liquidity_buffer_bps(0);
+        set_context_at("bob.testnet", 0, 0, 3);
+        contract.withdraw(Ledger::Player, U128(3 * MIN_DEPOSIT));
+
+        assert_eq!(contract.liquid_buffer, 0);
+        assert_eq!(contract.total_unstaking, 2 * MIN_DEPOSIT);
+        assert_eq!(
+            function_calls(),
+            vec![(account(CONTRACT_ID), "on_withdraw_transfer".to_string(), 0)]
+        );
+        let pending = contract.get_pending_withdrawals(account("bob.testnet"));
+        assert_eq!(pending[0].amount, U128(2 * MIN_DEPOSIT));
+    }
+
+    #[test]
+    fn test_withdraw_pays_what_is_not_staked() {
+        let mut contract = setup();
+        play(&mut contract, "bob.testnet", 10 * MIN_DEPOSIT);
+        set_context("bob.testnet", 0);
+        contract.withdraw_all(Ledger::Player);
+
+        assert_eq!(contract.total_stake, 0);
+        assert_eq!(contract.liquid_buffer, 0);
+        assert_eq!(contract.total_unstaking, 9 * MIN_DEPOSIT);
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        assert_eq!(receipts[0].receiver_id, account("bob.testnet"));
+    }
+
+    #[test]
+    fn test_failed_instant_transfer_refunds_fee() {
+        let mut contract = setup();
+        play(&mut contract, "bob.testnet", 10 * MIN_DEPOSIT);
+        set_context("bob.testnet", 0);
+        let paid = contract.instant_withdraw(U128(MIN_DEPOSIT));
+
+        set_callback_context(vec![PromiseResult::Failed]);
+        assert!(!contract.on_instant_withdraw_transfer(
+            account("bob.testnet"),
+            paid,
+            U128(fee(MIN_DEPOSIT))
+        ));
+        assert_eq!(contract.liquid_buffer, MIN_DEPOSIT);
+        assert_eq!(contract.total_stake, 9 * MIN_DEPOSIT);
+        assert_eq!(
+            contract.players.get(&account("bob.testnet")),
+            Some(10 * MIN_DEPOSIT)
+        );
+        assert!(!near_sdk::test_utils::get_logs()
+            .iter()
+            .any(|log| log.contains("\"event\":\"instant_withdraw_fee\"")));
+    }
+
+    #[test]
+    fn test_owner_can_keep_everything_liquid() {
+        let mut contract = setup();
+        set_context("owner.testnet", 0);
+        contract.set_liquidity_buffer_bps(BASIS_POINTS);
+        play(&mut contract, "bob.testnet", 10 * MIN_DEPOSIT);
+        assert_eq!(contract.liquid_buffer, 10 * MIN_DEPOSIT);
+        assert!(function_calls().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Liquidity buffer can be at most 10000 basis points")]
+    fn test_buffer_cannot_exceed_principal() {
+        let mut contract = setup();
+        set_context("owner.testnet", 0);
+        contract.set_liquidity_buffer_bps(BASIS_POINTS + 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_only_owner_sets_instant_fee() {
+        let mut contract = setup();
+        set_context("bob.testnet", 0);
+        contract.set_instant_withdraw_fee(InstantWithdrawFee::default());
+    }
+}
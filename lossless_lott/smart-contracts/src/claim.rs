@@ -0,0 +1,370 @@
+//! Claimable prizes. A draw only records what each winner is owed, so a
+//! winner's account can never make the draw fail; winners pull their prizes
+//! out with `claim_prize`, which queues them like a player withdrawal.
+//! Prizes left unclaimed past the expiry go back into the jackpot. Players
+//! who opt into auto-compounding skip the claim: their prizes are added to
+//! their deposit straight away.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
+use near_sdk::{env, near_bindgen, AccountId, Balance};
+
+use crate::events;
+use crate::{Contract, ContractExt, Ledger};
+
+/// Four weeks, in nanoseconds.
+pub const DEFAULT_PRIZE_EXPIRY: u64 = 28 * 24 * 60 * 60 * 1_000_000_000;
+
+/// Expired prizes returned per draw, keeping the sweep's gas bounded.
+const MAX_EXPIRED_PER_SWEEP: u64 = 100;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ClaimablePrize {
+    pub round_id: u64,
+    pub amount: U128,
+    pub expires_at: U64,
+}
+
+/// An entry in the queue of prizes waiting to expire.
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, PartialEq)]
+pub struct PrizeExpiry {
+    pub account_id: AccountId,
+    pub round_id: u64,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Queues every unexpired prize the caller has won for withdrawal,
+    /// collected with `complete_withdrawal` once the pool releases it.
+    /// Returns the amount claimed.
+    pub fn claim_prize(&mut self) -> U128 {
+        let account_id = env::predecessor_account_id();
+        let now = env::block_timestamp();
+        let (claimable, kept): (Vec<_>, Vec<_>) = self
+            .prizes
+            .get(&account_id)
+            .unwrap_or_default()
+            .into_iter()
+            .partition(|prize| prize.expires_at.0 > now);
+        let amount: Balance = claimable.iter().map(|prize| prize.amount.0).sum();
+        assert!(amount > 0, "No prizes to claim");
+        self.set_prizes(&account_id, kept);
+
+        self.total_prizes -= amount;
+        self.queue_withdrawal(&account_id, Ledger::Player, amount);
+        env::log_str(&format!("{} claimed {} in prizes", account_id, amount));
+        U128(amount)
+    }
+
+    /// Prizes `account_id` can still claim.
+    pub fn get_prizes(&self, account_id: AccountId) -> Vec<ClaimablePrize> {
+        let now = env::block_timestamp();
+        self.prizes
+            .get(&account_id)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|prize| prize.expires_at.0 > now)
+            .collect()
+    }
+
+    /// Turns auto-compounding of the caller's future prizes on or off.
+    pub fn set_auto_compound(&mut self, enabled: bool) {
+        let account_id = env::predecessor_account_id();
+        if enabled {
+            self.auto_compound.insert(&account_id);
+        } else {
+            self.auto_compound.remove(&account_id);
+        }
+    }
+
+    pub fn get_auto_compound(&self, account_id: AccountId) -> bool {
+        self.auto_compound.contains(&account_id)
+    }
+
+    pub fn get_total_prizes(&self) -> U128 {
+        U128(self.total_prizes)
+    }
+
+    pub fn get_prize_expiry(&self) -> U64 {
+        U64(self.prize_expiry)
+    }
+
+    /// Changes how long prizes awarded from now on can be claimed.
+    pub fn set_prize_expiry(&mut self, prize_expiry: U64) {
+        self.assert_owner();
+        assert!(prize_expiry.0 > 0, "Prize expiry must be positive");
+        self.prize_expiry = prize_expiry.0;
+    }
+}
+
+impl Contract {
+    /// Pays a prize the way the winner asked for: compounded into their
+    /// player balance, or recorded for them to claim.
+    pub(crate) fn pay_prize(&mut self, account_id: &AccountId, round_id: u64, amount: Balance) {
+        if self.auto_compound.contains(account_id) {
+            // The prize is already staked; it just becomes principal, and
+            // shows up in the deposit history like any other deposit.
+            self.credit(account_id, Ledger::Player, amount);
+            self.record_deposit(account_id, Ledger::Player, amount, None);
+            self.total_stake += amount;
+            env::log_str(&format!(
+                "Compounded {} into {}'s deposit",
+                amount, account_id
+            ));
+        } else {
+            self.award_prize(account_id, round_id, amount);
+        }
+    }
+
+    /// Records a prize for the winner to claim. The amount stays staked.
+    pub(crate) fn award_prize(&mut self, account_id: &AccountId, round_id: u64, amount: Balance) {
+        let expires_at = env::block_timestamp() + self.prize_expiry;
+        let mut prizes = self.prizes.get(account_id).unwrap_or_default();
+        prizes.push(ClaimablePrize {
+            round_id,
+            amount: U128(amount),
+            expires_at: U64(expires_at),
+        });
+        self.set_prizes(account_id, prizes);
+        self.total_prizes += amount;
+
+        self.prize_expiries.insert(
+            &(expires_at, self.prizes_awarded),
+            &PrizeExpiry {
+                account_id: account_id.clone(),
+                round_id,
+            },
+        );
+        self.prizes_awarded += 1;
+    }
+
+    /// Moves prizes whose expiry has passed into the jackpot, earliest
+    /// expiry first, and returns how much was moved.
+    pub(crate) fn sweep_expired_prizes(&mut self) -> Balance {
+        let now = env::block_timestamp();
+        let mut swept = 0;
+        let mut processed = 0;
+        while processed < MAX_EXPIRED_PER_SWEEP {
+            let key = match self.prize_expiries.min() {
+                Some(key) if key.0 <= now => key,
+                _ => break,
+            };
+            let entry = self.prize_expiries.remove(&key).unwrap();
+            // A claimed prize is no longer in the list and is skipped.
+            let prizes = self.prizes.get(&entry.account_id).unwrap_or_default();
+            let (expired, kept): (Vec<_>, Vec<_>) = prizes
+                .into_iter()
+                .partition(|prize| prize.round_id == entry.round_id && prize.expires_at.0 <= now);
+            swept += expired.iter().map(|prize| prize.amount.0).sum::<Balance>();
+            self.set_prizes(&entry.account_id, kept);
+            processed += 1;
+        }
+        if swept > 0 {
+            self.total_prizes -= swept;
+            self.jackpot += swept;
+            events::emit("prizes_expired", json!({ "amount": U128(swept) }));
+        }
+        swept
+    }
+
+    fn set_prizes(&mut self, account_id: &AccountId, prizes: Vec<ClaimablePrize>) {
+        if prizes.is_empty() {
+            self.prizes.remove(account_id);
+        } else {
+            self.prizes.insert(account_id, &prizes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::round::DEFAULT_ROUND_DURATION;
+    use crate::test_utils::*;
+    use crate::{PendingWithdrawal, UNSTAKE_DELAY_EPOCHS};
+
+    /// bob wins a 5 ticket prize in round 0, settled at the end of the round.
+    fn setup_prize_won() -> Contract {
+        let mut contract = setup();
+        set_context("alice.testnet", 100 * MIN_DEPOSIT);
+        contract.invest(U128(100 * MIN_DEPOSIT));
+        set_context("bob.testnet", 10 * MIN_DEPOSIT);
+        contract.play(U128(10 * MIN_DEPOSIT));
+        contract.sync_stake();
+        set_context_at("keeper.testnet", 0, DEFAULT_ROUND_DURATION, 7);
+        contract.draw();
+        settle(&mut contract, 105 * MIN_DEPOSIT, DEFAULT_ROUND_DURATION);
+        contract
+    }
+
+    #[test]
+    fn test_draw_records_claimable_prize() {
+        let contract = setup_prize_won();
+        let prizes = contract.get_prizes(account("bob.testnet"));
+        assert_eq!(prizes.len(), 1);
+        assert_eq!(prizes[0].round_id, 0);
+        assert_eq!(prizes[0].amount, U128(5 * MIN_DEPOSIT));
+        assert_eq!(
+            prizes[0].expires_at,
+            U64(DEFAULT_ROUND_DURATION + DEFAULT_PRIZE_EXPIRY)
+        );
+        assert_eq!(contract.get_total_prizes(), U128(5 * MIN_DEPOSIT));
+        assert_eq!(
+            contract.players.get(&account("bob.testnet")),
+            Some(10 * MIN_DEPOSIT)
+        );
+    }
+
+    #[test]
+    fn test_claim_queues_prize() {
+        let mut contract = setup_prize_won();
+        set_context_at("bob.testnet", 0, DEFAULT_ROUND_DURATION + 1, 10);
+        assert_eq!(contract.claim_prize(), U128(5 * MIN_DEPOSIT));
+
+        assert!(contract.get_prizes(account("bob.testnet")).is_empty());
+        assert_eq!(contract.total_prizes, 0);
+        assert_eq!(contract.total_unstaking, 5 * MIN_DEPOSIT);
+        assert_eq!(
+            contract.get_pending_withdrawals(account("bob.testnet")),
+            vec![PendingWithdrawal {
+                amount: U128(5 * MIN_DEPOSIT),
+                available_epoch: 10 + UNSTAKE_DELAY_EPOCHS,
+                unstaked: false,
+            }]
+        );
+        assert_eq!(contract.get_unstake_queue(), vec![10]);
+        assert!(function_calls().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "No prizes to claim")]
+    fn test_claim_requires_prize() {
+        let mut contract = setup_prize_won();
+        set_context("alice.testnet", 0);
+        contract.claim_prize();
+    }
+
+    #[test]
+    #[should_panic(expected = "No prizes to claim")]
+    fn test_expired_prize_cannot_be_claimed() {
+        let mut contract = setup_prize_won();
+        let expiry = DEFAULT_ROUND_DURATION + DEFAULT_PRIZE_EXPIRY;
+        set_context_at("bob.testnet", 0, expiry, 10);
+        assert!(contract.get_prizes(account("bob.testnet")).is_empty());
+        contract.claim_prize();
+    }
+
+    #[test]
+    fn test_expired_prizes_return_to_next_prize() {
+        let mut contract = setup_prize_won();
+        let expiry = DEFAULT_ROUND_DURATION + DEFAULT_PRIZE_EXPIRY;
+        set_context_at("keeper.testnet", 0, expiry, 40);
+        contract.draw();
+
+        assert_eq!(contract.total_prizes, 0);
+        assert_eq!(contract.get_jackpot(), U128(5 * MIN_DEPOSIT));
+        assert!(contract.prizes.get(&account("bob.testnet")).is_none());
+
+        // Nothing new was earned, yet the round pays out the expired prize.
+        let round = settle(&mut contract, 105 * MIN_DEPOSIT, expiry);
+        assert_eq!(round.prize, U128(5 * MIN_DEPOSIT));
+        assert_eq!(round.jackpot, U128(5 * MIN_DEPOSIT));
+    }
+
+    #[test]
+    fn test_claimed_prizes_are_not_swept() {
+        let mut contract = setup_prize_won();
+        set_context_at("bob.testnet", 0, DEFAULT_ROUND_DURATION + 1, 10);
+        contract.claim_prize();
+
+        set_context_at(
+            "keeper.testnet",
+            0,
+            DEFAULT_ROUND_DURATION + DEFAULT_PRIZE_EXPIRY,
+            40,
+        );
+        assert_eq!(contract.sweep_expired_prizes(), 0);
+        assert_eq!(contract.prize_expiries.len(), 0);
+    }
+
+    #[test]
+    fn test_shorter_expiry_is_swept_ahead_of_older_prizes() {
+        let mut contract = setup_prize_won();
+        let one_day = DEFAULT_PRIZE_EXPIRY / 28;
+        set_context("owner.testnet", 0);
+        contract.set_prize_expiry(U64(one_day));
+        set_context_at("keeper.testnet", 0, 2 * DEFAULT_ROUND_DURATION, 8);
+        contract.draw();
+        settle(&mut contract, 108 * MIN_DEPOSIT, 2 * DEFAULT_ROUND_DURATION);
+        assert_eq!(contract.total_prizes, 8 * MIN_DEPOSIT);
+
+        // Round 1's prize expires first even though round 0's was awarded
+        // before it.
+        set_context_at("keeper.testnet", 0, 2 * DEFAULT_ROUND_DURATION + one_day, 9);
+        assert_eq!(contract.sweep_expired_prizes(), 3 * MIN_DEPOSIT);
+        assert_eq!(contract.get_jackpot(), U128(3 * MIN_DEPOSIT));
+        let prizes = contract.get_prizes(account("bob.testnet"));
+        assert_eq!(prizes.len(), 1);
+        assert_eq!(prizes[0].round_id, 0);
+    }
+
+    #[test]
+    fn test_auto_compound_adds_prize_to_deposit() {
+        let mut contract = setup();
+        set_context("alice.testnet", 100 * MIN_DEPOSIT);
+        contract.invest(U128(100 * MIN_DEPOSIT));
+        set_context("bob.testnet", 10 * MIN_DEPOSIT);
+        contract.play(U128(10 * MIN_DEPOSIT));
+        contract.set_auto_compound(true);
+        contract.sync_stake();
+        set_context_at("keeper.testnet", 0, DEFAULT_ROUND_DURATION, 7);
+        contract.draw();
+        settle(&mut contract, 105 * MIN_DEPOSIT, DEFAULT_ROUND_DURATION);
+
+        assert!(contract.get_prizes(account("bob.testnet")).is_empty());
+        assert_eq!(
+            contract.players.get(&account("bob.testnet")),
+            Some(15 * MIN_DEPOSIT)
+        );
+        assert_eq!(contract.total_stake, 105 * MIN_DEPOSIT);
+        assert_eq!(contract.total_prizes, 0);
+        // The compounded prize holds tickets for the whole next round.
+        assert_eq!(
+            contract.get_twab(account("bob.testnet")),
+            U128(15 * MIN_DEPOSIT)
+        );
+        let history = contract.get_deposit_history(account("bob.testnet"), None, None);
+        let deposited: Balance = history.iter().map(|record| record.amount.0).sum();
+        assert_eq!(history.len(), 2);
+        assert_eq!(deposited, 15 * MIN_DEPOSIT);
+    }
+
+    #[test]
+    fn test_auto_compound_opt_out() {
+        let mut contract = setup();
+        assert!(!contract.get_auto_compound(account("bob.testnet")));
+        set_context("bob.testnet", 0);
+        contract.set_auto_compound(true);
+        assert!(contract.get_auto_compound(account("bob.testnet")));
+        contract.set_auto_compound(false);
+        assert!(!contract.get_auto_compound(account("bob.testnet")));
+    }
+
+    #[test]
+    fn test_owner_sets_prize_expiry() {
+        let mut contract = setup();
+        set_context("owner.testnet", 0);
+        contract.set_prize_expiry(U64(60));
+        assert_eq!(contract.get_prize_expiry(), U64(60));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_prize_expiry_is_owner_only() {
+        let mut contract = setup();
+        set_context("bob.testnet", 0);
+        contract.set_prize_expiry(U64(60));
+    }
+}
@@ -0,0 +1,17 @@
+//! NEP-297 events, logged as `EVENT_JSON:` lines so indexers can follow the
+//! contract without parsing free-form logs.
+use near_sdk::env;
+use near_sdk::serde_json::{json, Value};
+
+pub const EVENT_STANDARD: &str = "lossless_lott";
+pub const EVENT_VERSION: &str = "1.0.0";
+
+pub(crate) fn emit(event: &str, data: Value) {
+    let event = json!({
+        "standard": EVENT_STANDARD,
+        "version": EVENT_VERSION,
+        "event": event,
+        "data": [data],
+    });
+    env::log_str(&format!("EVENT_JSON:{}", event));
+}
@@ -0,0 +1,227 @@
+//! A Fenwick tree of time-weighted tickets kept in contract storage, so a
+//! draw reads O(log n) slots however many players there are.
+//!
+//! Each node holds two sums over its range: tickets, which persist from
+//! round to round, and a per-round deficit, the ticket-seconds not held
+//! because tickets were added part-way through the round. A slot's weight is
+//! `tickets * round_seconds - deficit`. Deficits are tagged with the round
+//! they belong to and read as zero once stale, which resets the whole tree
+//! for a new round without touching it.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::IntoStorageKey;
+
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, Default, PartialEq)]
+pub struct Node {
+    pub tickets: u128,
+    pub round_id: u64,
+    pub deficit: i128,
+}
+
+impl Node {
+    fn weight(&self, round_id: u64, round_seconds: u64) -> u128 {
+        let deficit = if self.round_id == round_id {
+            self.deficit
+        } else {
+            0
+        };
+        (self.tickets as i128 * round_seconds as i128 - deficit) as u128
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct FenwickTree {
+    nodes: LookupMap<u64, Node>,
+    /// Number of slots handed out; slots are numbered from 1.
+    len: u64,
+    /// Always a power of two (or zero) so the root covers every slot.
+    capacity: u64,
+}
+
+impl FenwickTree {
+    pub fn new<S: IntoStorageKey>(prefix: S) -> Self {
+        Self {
+            nodes: LookupMap::new(prefix),
+            len: 0,
+            capacity: 0,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Hands out the next slot. Doubling the capacity only needs the old
+    /// root copied to the new one, since every new slot starts empty.
+    pub fn push(&mut self) -> u64 {
+        if self.len == self.capacity {
+            let old_root = self.capacity;
+            self.capacity = std::cmp::max(1, self.capacity * 2);
+            if let Some(root) = self.nodes.get(&old_root) {
+                self.nodes.insert(&self.capacity, &root);
+            }
+        }
+        self.len += 1;
+        self.len
+    }
+
+    pub fn update(&mut self, slot: u64, round_id: u64, tickets: i128, deficit: i128) {
+        assert!(slot >= 1 && slot <= self.len, "Slot out of range");
+        let mut index = slot;
+        while index <= self.capacity {
+            let mut node = self.nodes.get(&index).unwrap_or_default();
+            if node.round_id != round_id {
+                node.round_id = round_id;
+                node.deficit = 0;
+            }
+            node.tickets = (node.tickets as i128 + tickets) as u128;
+            node.deficit += deficit;
+            self.nodes.insert(&index, &node);
+            index += index & index.wrapping_neg();
+        }
+    }
+
+    pub fn total(&self, round_id: u64, round_seconds: u64) -> u128 {
+        self.nodes
+            .get(&self.capacity)
+            .map(|root| root.weight(round_id, round_seconds))
+            .unwrap_or(0)
+    }
+
+    /// Returns the slot owning the zero-based `ticket`, descending from the
+    /// root one level per step.
+    pub fn find(&self, round_id: u64, round_seconds: u64, ticket: u128) -> u64 {
+        self.find_excluding(round_id, round_seconds, ticket, &[])
+    }
+
+    /// Like `find`, but as if the `(slot, weight)` pairs in `excluded` had
+    /// been taken out of the tree. Draws without replacement use this to skip
+    /// earlier winners without writing to storage.
+    pub fn find_excluding(
+        &self,
+        round_id: u64,
+        round_seconds: u64,
+        mut ticket: u128,
+        excluded: &[(u64, u128)],
+    ) -> u64 {
+        let excluded_total: u128 = excluded.iter().map(|&(_, weight)| weight).sum();
+        assert!(
+            ticket + excluded_total < self.total(round_id, round_seconds),
+            "Ticket out of range"
+        );
+        let mut position = 0;
+        let mut step = self.capacity;
+        while step > 0 {
+            let next = position + step;
+            if let Some(node) = self.nodes.get(&next) {
+                let skipped: u128 = excluded
+                    .iter()
+                    .filter(|&&(slot, _)| slot > position && slot <= next)
+                    .map(|&(_, weight)| weight)
+                    .sum();
+                let weight = node.weight(round_id, round_seconds) - skipped;
+                if weight <= ticket {
+                    position = next;
+                    ticket -= weight;
+                }
+            }
+            step /= 2;
+        }
+        position + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selection::TicketIndex;
+    use crate::test_utils::account;
+
+    fn tree_with(weights: &[u128]) -> FenwickTree {
+        let mut tree = FenwickTree::new(b"f");
+        for &tickets in weights {
+            let slot = tree.push();
+            tree.update(slot, 0, tickets as i128, 0);
+        }
+        tree
+    }
+
+    #[test]
+    fn test_find_matches_linear_index() {
+        let weights = [3, 0, 1, 7, 2, 0, 0, 5, 4];
+        let tree = tree_with(&weights);
+        let index = TicketIndex::new(
+            weights
+                .iter()
+                .enumerate()
+                .map(|(i, &tickets)| (account(&format!("p{}.testnet", i + 1)), tickets * 10)),
+        );
+        assert_eq!(tree.total(0, 10), index.total());
+        for ticket in 0..index.total() {
+            let slot = tree.find(0, 10, ticket);
+            assert_eq!(
+                &account(&format!("p{}.testnet", slot)),
+                index.owner_of(ticket)
+            );
+        }
+    }
+
+    #[test]
+    fn test_find_excluding_skips_removed_slots() {
+        let weights = [3, 0, 1, 7, 2, 0, 0, 5, 4];
+        let tree = tree_with(&weights);
+        let excluded = [(4, 70), (9, 40)];
+        let index = TicketIndex::new(
+            weights
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| i != 3 && i != 8)
+                .map(|(i, &tickets)| (account(&format!("p{}.testnet", i + 1)), tickets * 10)),
+        );
+        assert_eq!(tree.total(0, 10) - 110, index.total());
+        for ticket in 0..index.total() {
+            let slot = tree.find_excluding(0, 10, ticket, &excluded);
+            assert_eq!(
+                &account(&format!("p{}.testnet", slot)),
+                index.owner_of(ticket)
+            );
+        }
+    }
+
+    #[test]
+    fn test_deficit_reduces_weight_for_its_round_only() {
+        let mut tree = tree_with(&[2, 2]);
+        tree.update(1, 0, 0, 15);
+        assert_eq!(tree.total(0, 10), 25);
+        assert_eq!(tree.find(0, 10, 4), 1);
+        assert_eq!(tree.find(0, 10, 5), 2);
+        // A new round starts with every slot holding its tickets in full.
+        assert_eq!(tree.total(1, 10), 40);
+        assert_eq!(tree.find(1, 10, 19), 1);
+        assert_eq!(tree.find(1, 10, 20), 2);
+    }
+
+    #[test]
+    fn test_growth_keeps_existing_weights() {
+        let mut tree = tree_with(&[1, 1, 1]);
+        assert_eq!(tree.total(0, 1), 3);
+        for _ in 0..6 {
+            tree.push();
+        }
+        tree.update(9, 0, 4, 0);
+        assert_eq!(tree.len(), 9);
+        assert_eq!(tree.total(0, 1), 7);
+        assert_eq!(tree.find(0, 1, 2), 3);
+        assert_eq!(tree.find(0, 1, 3), 9);
+    }
+
+    #[test]
+    #[should_panic(expected = "Ticket out of range")]
+    fn test_find_in_empty_tree() {
+        FenwickTree::new(b"f").find(0, 1, 0);
+    }
+}
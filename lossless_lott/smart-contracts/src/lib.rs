@@ -1,93 +1,446 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::UnorderedMap;
-use near_sdk::{env, near_bindgen, AccountId, Balance, PanicOnDefault, Promise, U128};
-use rand::{Rng, SeedableRng};
-use rand::rngs::StdRng;
-use std::convert::TryInto;
+use near_sdk::collections::{LookupMap, LookupSet, TreeMap, UnorderedMap, UnorderedSet, Vector};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    env, near_bindgen, AccountId, Balance, Gas, PanicOnDefault, Promise, PromiseResult,
+    StorageUsage,
+};
+
+pub mod buffer;
+pub mod claim;
+pub mod events;
+pub mod fenwick;
+pub mod pools;
+pub mod prize;
+pub mod receipt;
+pub mod round;
+pub mod selection;
+pub mod share_token;
+pub mod split;
+pub mod staking;
+pub mod sync;
+#[cfg(test)]
+mod test_utils;
+pub mod tokens;
+pub mod twab;
+pub mod vault;
+pub mod withdrawals;
+
+pub use buffer::{FeeRecipient, InstantWithdrawFee, LiquidityBuffer};
+pub use claim::ClaimablePrize;
+pub use fenwick::FenwickTree;
+pub use pools::StakingPool;
+pub use prize::{PrizeTier, YieldMeasurement};
+pub use receipt::{DrawReceipt, Participant, TwabUpdate, Winner};
+pub use round::{Round, RoundStatus};
+pub use split::{YieldBuckets, YieldSplit};
+pub use staking::PoolBalance;
+pub use sync::StakeSync;
+pub use tokens::TokenTotals;
+pub use twab::Twab;
+pub use withdrawals::{PendingWithdrawal, WithdrawalBatch};
+
+/// Number of epochs a staking pool keeps unstaked funds locked.
+pub const UNSTAKE_DELAY_EPOCHS: u64 = 4;
+
+const GAS_FOR_ON_WITHDRAW_TRANSFER: Gas = Gas(10_000_000_000_000);
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct Contract {
     pub owner_id: AccountId,
-    pub staking_contract: AccountId,
+    /// Pools the stake is spread over, in the order they were added.
+    pub staking_pools: Vec<StakingPool>,
+    /// Vault shares held by each investor.
     pub investors: UnorderedMap<AccountId, Balance>,
     pub players: UnorderedMap<AccountId, Balance>,
+    /// Principal owed to investors and players that is staked in the pool,
+    /// or waiting in `pending_stake` to be.
     pub total_stake: Balance,
     pub min_deposit: Balance,
+    pub deposit_history: LookupMap<AccountId, Vector<DepositRecord>>,
+    /// Each account's queued withdrawals, oldest first.
+    pub withdrawal_requests: LookupMap<AccountId, Vec<withdrawals::WithdrawalRequest>>,
+    pub withdrawal_batches: LookupMap<u64, WithdrawalBatch>,
+    /// Epochs of batches not yet settled by `sync_stake`.
+    pub unstake_queue: Vector<u64>,
+    /// Deposits waiting for `sync_stake`.
+    pub pending_stake: Balance,
+    /// Part of `total_unstaking` that deposits already cover, held by the
+    /// contract.
+    pub withdrawal_reserve: Balance,
+    pub last_sync_epoch: Option<u64>,
+    /// Withdrawals a pool refused to unstake, retried at the next sync.
+    pub unstake_shortfall: Balance,
+    /// Batches reopened by a refused unstake, stamped again by the retry.
+    pub shortfall_batches: Vec<u64>,
+    /// Principal unstaked for withdrawals but not yet pulled out of the pool.
+    pub total_unstaking: Balance,
+    pub round_id: u64,
+    pub round_yields: LookupMap<u64, YieldMeasurement>,
+    pub rounds: LookupMap<u64, Round>,
+    /// Length of newly opened rounds, in nanoseconds.
+    pub round_duration: u64,
+    pub twabs: LookupMap<AccountId, Twab>,
+    pub player_slots: LookupMap<AccountId, u64>,
+    /// Owner of each tree slot; slot `n` is stored at index `n - 1`.
+    pub slot_owners: Vector<AccountId>,
+    pub twab_tree: FenwickTree,
+    /// Hash chain over every `twab_update` event, and how many it covers.
+    pub twab_hash: Vec<u8>,
+    pub twab_updates: u64,
+    pub draw_receipts: LookupMap<u64, DrawReceipt>,
+    pub prize_tiers: Vec<PrizeTier>,
+    /// Yield carried over from rounds that paid out less than their prize.
+    /// It is still in the pool, so the next measured prize already holds it.
+    pub jackpot: Balance,
+    /// Prizes awaiting a claim, per winner.
+    pub prizes: LookupMap<AccountId, Vec<ClaimablePrize>>,
+    /// Unclaimed prizes, still staked in the pool.
+    pub total_prizes: Balance,
+    /// How long a prize can be claimed for, in nanoseconds.
+    pub prize_expiry: u64,
+    /// Unswept prizes keyed by expiry time, then award order, so the
+    /// earliest to expire comes first whatever expiry it was awarded with.
+    pub prize_expiries: TreeMap<(u64, u64), claim::PrizeExpiry>,
+    /// Prizes awarded so far, numbering the entries in `prize_expiries`.
+    pub prizes_awarded: u64,
+    /// Players whose prizes are added to their deposit instead of waiting
+    /// for a claim.
+    pub auto_compound: LookupSet<AccountId>,
+    pub yield_split: YieldSplit,
+    /// Yield the split has paid into the investor vault so far.
+    pub investor_return: Balance,
+    pub protocol_fees: Balance,
+    pub reserve_fund: Balance,
+    pub total_shares: Balance,
+    /// Investor principal plus the yield it has earned; part of `total_stake`.
+    pub vault_assets: Balance,
+    /// Accounts registered to hold shares, with the storage deposit each
+    /// paid.
+    pub share_storage: LookupMap<AccountId, Balance>,
+    pub share_storage_usage: StorageUsage,
+    /// NEP-141 tokens the contract takes deposits in.
+    pub accepted_tokens: UnorderedSet<AccountId>,
+    pub token_totals: LookupMap<AccountId, TokenTotals>,
+    /// Token deposits, keyed by token, ledger and depositor.
+    pub token_balances: LookupMap<(AccountId, Ledger, AccountId), Balance>,
+    /// Share of principal, in basis points, kept unstaked for withdrawals.
+    pub liquidity_buffer_bps: u32,
+    /// Principal held unstaked by the contract.
+    pub liquid_buffer: Balance,
+    pub instant_withdraw_fee: InstantWithdrawFee,
+}
+
+/// The two balances an account can hold in the contract.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum Ledger {
+    Investor,
+    Player,
+}
+
+/// A single deposit as seen by the contract, kept so support can reconstruct
+/// how an account's balance was built up.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DepositRecord {
+    pub ledger: Ledger,
+    /// Token the deposit was made in, or `None` for NEAR.
+    pub token_id: Option<AccountId>,
+    /// Amount deposited, in the token's own units.
+    pub amount: U128,
+    pub block_timestamp: U64,
+    pub epoch_height: u64,
 }
 
 #[near_bindgen]
 impl Contract {
+    /// Starts with `staking_contract` as the only staking pool; the owner
+    /// can add more with `add_staking_pool`.
     #[init]
     pub fn new(owner_id: AccountId, staking_contract: AccountId, min_deposit: U128) -> Self {
-        Self {
+        let mut this = Self {
             owner_id,
-            staking_contract,
+            staking_pools: vec![StakingPool::new(staking_contract, 1)],
             investors: UnorderedMap::new(b"i"),
             players: UnorderedMap::new(b"p"),
             total_stake: 0,
             min_deposit: min_deposit.0,
-        }
+            deposit_history: LookupMap::new(b"h"),
+            withdrawal_requests: LookupMap::new(b"w"),
+            withdrawal_batches: LookupMap::new(b"m"),
+            unstake_queue: Vector::new(b"v"),
+            pending_stake: 0,
+            withdrawal_reserve: 0,
+            last_sync_epoch: None,
+            unstake_shortfall: 0,
+            shortfall_batches: vec![],
+            total_unstaking: 0,
+            round_id: 0,
+            round_yields: LookupMap::new(b"y"),
+            rounds: LookupMap::new(b"r"),
+            round_duration: round::DEFAULT_ROUND_DURATION,
+            twabs: LookupMap::new(b"t"),
+            player_slots: LookupMap::new(b"s"),
+            slot_owners: Vector::new(b"o"),
+            twab_tree: FenwickTree::new(b"f"),
+            twab_hash: vec![],
+            twab_updates: 0,
+            draw_receipts: LookupMap::new(b"x"),
+            prize_tiers: PrizeTier::single(),
+            jackpot: 0,
+            prizes: LookupMap::new(b"c"),
+            total_prizes: 0,
+            prize_expiry: claim::DEFAULT_PRIZE_EXPIRY,
+            prize_expiries: TreeMap::new(b"e"),
+            prizes_awarded: 0,
+            auto_compound: LookupSet::new(b"a"),
+            yield_split: YieldSplit::default(),
+            investor_return: 0,
+            protocol_fees: 0,
+            reserve_fund: 0,
+            total_shares: 0,
+            vault_assets: 0,
+            share_storage: LookupMap::new(b"b"),
+            share_storage_usage: 0,
+            accepted_tokens: UnorderedSet::new(b"k"),
+            token_totals: LookupMap::new(b"g"),
+            token_balances: LookupMap::new(b"u"),
+            liquidity_buffer_bps: buffer::DEFAULT_LIQUIDITY_BUFFER_BPS,
+            liquid_buffer: 0,
+            instant_withdraw_fee: InstantWithdrawFee::default(),
+        };
+        this.share_storage_usage = this.measure_share_storage_usage();
+        let first = Round::open(0, env::block_timestamp(), this.round_duration);
+        this.rounds.insert(&first.id, &first);
+        this
     }
 
+    #[payable]
     pub fn invest(&mut self, amount: U128) {
         let investor = env::predecessor_account_id();
-        let investment_amount = amount.0;
-        
-        self.investors.insert(&investor, &investment_amount);
-        self.total_stake += investment_amount;
-        
-        Promise::new(self.staking_contract.clone())
-            .function_call("stake".to_string(), amount.into(), 0, env::prepaid_gas() / 2);
+        let investment_amount = assert_attached_deposit(amount);
+
+        self.mint_shares(&investor, investment_amount);
+        self.record_deposit(&investor, Ledger::Investor, investment_amount, None);
+        self.pending_stake += investment_amount;
     }
 
+    #[payable]
     pub fn play(&mut self, amount: U128) {
         let player = env::predecessor_account_id();
-        let play_amount = amount.0;
+        let play_amount = assert_attached_deposit(amount);
         assert!(play_amount >= self.min_deposit, "Deposit too low");
-        
-        self.players.insert(&player, &play_amount);
+
+        self.credit(&player, Ledger::Player, play_amount);
+        self.record_deposit(&player, Ledger::Player, play_amount, None);
+        self.deposit_principal(play_amount);
+    }
+
+    /// Takes `amount` out of the caller's balance in `ledger`. It joins the
+    /// withdrawal queue and becomes claimable through `complete_withdrawal`
+    /// once the staking pool releases it; players who cannot wait use
+    /// `instant_withdraw`. Token deposits come out through `withdraw_token`
+    /// instead.
+    pub fn withdraw(&mut self, ledger: Ledger, amount: U128) {
+        let account_id = env::predecessor_account_id();
+        let amount = amount.0;
+        assert!(amount > 0, "Withdrawal amount must be greater than zero");
+        self.internal_withdraw(&account_id, ledger, amount);
+    }
+
+    /// Withdraws the caller's whole balance in `ledger`; for investors, that
+    /// is every share they hold.
+    pub fn withdraw_all(&mut self, ledger: Ledger) {
+        let account_id = env::predecessor_account_id();
+        let balance = self.ledger_balance(&account_id, ledger);
+        assert!(balance > 0, "Nothing to withdraw");
+        match ledger {
+            Ledger::Player => self.internal_withdraw(&account_id, ledger, balance),
+            Ledger::Investor => {
+                self.redeem_shares(&account_id, balance);
+            }
+        }
+    }
+
+    /// Puts the funds back where they came from if the transfer to the
+    /// account did not go through. A player's payout, principal or prize,
+    /// goes back on their balance and into the buffer; an investor's is
+    /// queued to be completed again.
+    #[private]
+    pub fn on_withdraw_transfer(&mut self, account_id: AccountId, ledger: Ledger, amount: U128) {
+        if is_promise_success() {
+            return;
+        }
+        env::log_str(&format!(
+            "Transfer of {} to {} failed, restoring balance",
+            amount.0, account_id
+        ));
+        match ledger {
+            Ledger::Player => {
+                self.credit(&account_id, ledger, amount.0);
+                self.liquid_buffer += amount.0;
+            }
+            Ledger::Investor => {
+                self.withdrawal_reserve += amount.0;
+                self.total_unstaking += amount.0;
+                self.restore_withdrawal(&account_id, ledger, amount.0, amount.0);
+            }
+        }
+    }
+
+    /// Returns up to `limit` deposits made by `account_id`, oldest first.
+    pub fn get_deposit_history(
+        &self,
+        account_id: AccountId,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<DepositRecord> {
+        let history = match self.deposit_history.get(&account_id) {
+            Some(history) => history,
+            None => return vec![],
+        };
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(history.len());
+        (from_index..std::cmp::min(from_index.saturating_add(limit), history.len()))
+            .filter_map(|index| history.get(index))
+            .collect()
     }
 
     pub fn total_investment(&self) -> Balance {
-        self.investors.values_as_vector().iter().sum()
+        self.vault_assets
     }
 
-    fn total_player_deposit(&self) -> Balance {
+    pub fn total_player_deposit(&self) -> Balance {
         self.players.values_as_vector().iter().sum()
     }
 
-    fn simulate_profit(&self) -> Balance {
-        self.total_stake / 10
+    fn ledger_balance(&self, account_id: &AccountId, ledger: Ledger) -> Balance {
+        match ledger {
+            Ledger::Investor => self.investors.get(account_id),
+            Ledger::Player => self.players.get(account_id),
+        }
+        .unwrap_or(0)
     }
 
-    fn select_winner(&self) -> AccountId {
-        let players: Vec<AccountId> = self.players.keys_as_vector().to_vec();
-        assert!(!players.is_empty(), "No players to select a winner from");
+    fn credit(&mut self, account_id: &AccountId, ledger: Ledger, amount: Balance) {
+        let old_balance = self.ledger_balance(account_id, ledger);
+        let balance = old_balance + amount;
+        match ledger {
+            Ledger::Investor => {
+                self.investors.insert(account_id, &balance);
+            }
+            Ledger::Player => {
+                self.update_twab(account_id, old_balance, balance);
+                self.players.insert(account_id, &balance);
+            }
+        }
+    }
 
-        let random_seed = env::random_seed();
-        let seed_array: [u8; 32] = {
-            let mut seed = [0u8; 32];
-            let bytes_to_copy = std::cmp::min(random_seed.len(), 32);
-            seed[..bytes_to_copy].copy_from_slice(&random_seed[..bytes_to_copy]);
-            seed
+    /// Removes `amount` from the account's balance, dropping the entry
+    /// entirely once it reaches zero. A player keeps the weight they
+    /// accrued while holding the tickets, however much they take out.
+    fn debit(&mut self, account_id: &AccountId, ledger: Ledger, amount: Balance) {
+        let balance = self.ledger_balance(account_id, ledger);
+        assert!(amount <= balance, "Withdrawal exceeds balance");
+        let remaining = balance - amount;
+        let accounts = match ledger {
+            Ledger::Investor => &mut self.investors,
+            Ledger::Player => &mut self.players,
         };
-        let mut rng = StdRng::from_seed(seed_array);
-        let winner_index = rng.gen_range(0..players.len());
-        players[winner_index].clone()
+        if remaining == 0 {
+            accounts.remove(account_id);
+        } else {
+            accounts.insert(account_id, &remaining);
+        }
+        if ledger == Ledger::Player {
+            self.update_twab(account_id, balance, remaining);
+        }
+    }
+
+    /// Investors withdraw `amount` of assets by burning the shares it is
+    /// worth, rounded up.
+    fn internal_withdraw(&mut self, account_id: &AccountId, ledger: Ledger, amount: Balance) {
+        match ledger {
+            Ledger::Player => {
+                self.debit(account_id, ledger, amount);
+                self.withdraw_principal(account_id, amount);
+            }
+            Ledger::Investor => {
+                let shares = self.shares_for(amount, true);
+                self.burn_shares(account_id, shares, amount);
+                self.unstake_investor(account_id, amount);
+            }
+        }
+    }
+
+    fn transfer_to(&mut self, account_id: AccountId, ledger: Ledger, amount: Balance) {
+        Promise::new(account_id.clone()).transfer(amount).then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_ON_WITHDRAW_TRANSFER)
+                .on_withdraw_transfer(account_id, ledger, U128(amount)),
+        );
+    }
+
+    fn record_deposit(
+        &mut self,
+        account_id: &AccountId,
+        ledger: Ledger,
+        amount: Balance,
+        token_id: Option<AccountId>,
+    ) {
+        let mut history = self.deposit_history.get(account_id).unwrap_or_else(|| {
+            let mut prefix = b"d".to_vec();
+            prefix.extend(env::sha256(account_id.as_bytes()));
+            Vector::new(prefix)
+        });
+        history.push(&DepositRecord {
+            ledger,
+            token_id,
+            amount: U128(amount),
+            block_timestamp: U64(env::block_timestamp()),
+            epoch_height: env::epoch_height(),
+        });
+        self.deposit_history.insert(account_id, &history);
+    }
+
+    fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "Only the owner can call this method"
+        );
     }
 }
 
-#[ext_contract(ext_staking_contract)]
-trait StakingContract {
-    fn stake(&mut self, amount: U128);
+pub(crate) fn is_promise_success() -> bool {
+    assert_eq!(
+        env::promise_results_count(),
+        1,
+        "Expected exactly one promise result"
+    );
+    matches!(env::promise_result(0), PromiseResult::Successful(_))
+}
+
+/// Returns the attached deposit after checking it is non-zero and equal to the
+/// `amount` the caller claims to be depositing.
+fn assert_attached_deposit(amount: U128) -> Balance {
+    let deposit = env::attached_deposit();
+    assert!(deposit > 0, "Attached deposit must be greater than zero");
+    assert_eq!(deposit, amount.0, "Attached deposit does not match amount");
+    deposit
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use near_sdk::test_utils::VMContextBuilder;
-    use near_sdk::{testing_env, AccountId};
+    use crate::test_utils::*;
+    use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+    use near_sdk::testing_env;
 
     #[test]
     fn test_initialization() {
@@ -104,4 +457,313 @@ mod tests {
         );
         assert_eq!(contract.min_deposit, 1_000_000);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_invest_credits_attached_deposit() {
+        let mut contract = setup();
+        set_context("alice.testnet", 5 * MIN_DEPOSIT);
+        contract.invest(U128(5 * MIN_DEPOSIT));
+
+        let credited = contract.investors.get(&account("alice.testnet")).unwrap();
+        assert_eq!(credited, 5 * MIN_DEPOSIT);
+        assert_eq!(contract.total_stake, 5 * MIN_DEPOSIT);
+        // The deposit waits for the next sync rather than being staked alone.
+        assert_eq!(contract.pending_stake, credited);
+        assert!(function_calls().is_empty());
+    }
+
+    #[test]
+    fn test_play_credits_attached_deposit() {
+        let mut contract = setup();
+        set_context("bob.testnet", 2 * MIN_DEPOSIT);
+        contract.play(U128(2 * MIN_DEPOSIT));
+
+        let credited = contract.players.get(&account("bob.testnet")).unwrap();
+        assert_eq!(credited, 2 * MIN_DEPOSIT);
+        // Whatever the buffer does not keep is staked.
+        let staked: Balance = function_calls().iter().map(|call| call.2).sum();
+        assert_eq!(env::account_balance() + staked - INITIAL_BALANCE, credited);
+        assert_eq!(contract.liquid_buffer + contract.total_stake, credited);
+    }
+
+    #[test]
+    #[should_panic(expected = "Attached deposit must be greater than zero")]
+    fn test_invest_rejects_zero_deposit() {
+        let mut contract = setup();
+        set_context("alice.testnet", 0);
+        contract.invest(U128(MIN_DEPOSIT));
+    }
+
+    #[test]
+    #[should_panic(expected = "Attached deposit does not match amount")]
+    fn test_invest_rejects_mismatched_deposit() {
+        let mut contract = setup();
+        set_context("alice.testnet", MIN_DEPOSIT);
+        contract.invest(U128(100 * MIN_DEPOSIT));
+    }
+
+    #[test]
+    #[should_panic(expected = "Attached deposit must be greater than zero")]
+    fn test_play_rejects_zero_deposit() {
+        let mut contract = setup();
+        set_context("bob.testnet", 0);
+        contract.play(U128(MIN_DEPOSIT));
+    }
+
+    #[test]
+    #[should_panic(expected = "Attached deposit does not match amount")]
+    fn test_play_rejects_mismatched_deposit() {
+        let mut contract = setup();
+        set_context("bob.testnet", MIN_DEPOSIT);
+        contract.play(U128(2 * MIN_DEPOSIT));
+    }
+
+    #[test]
+    #[should_panic(expected = "Deposit too low")]
+    fn test_play_rejects_deposit_below_minimum() {
+        let mut contract = setup();
+        set_context("bob.testnet", MIN_DEPOSIT - 1);
+        contract.play(U128(MIN_DEPOSIT - 1));
+    }
+
+    #[test]
+    fn test_invest_top_up_accumulates() {
+        let mut contract = setup();
+        set_context_at("alice.testnet", 3 * MIN_DEPOSIT, 100, 1);
+        contract.invest(U128(3 * MIN_DEPOSIT));
+        set_context_at("alice.testnet", 2 * MIN_DEPOSIT, 200, 2);
+        contract.invest(U128(2 * MIN_DEPOSIT));
+
+        assert_eq!(
+            contract.investors.get(&account("alice.testnet")),
+            Some(5 * MIN_DEPOSIT)
+        );
+        assert_eq!(contract.total_stake, 5 * MIN_DEPOSIT);
+        assert_eq!(contract.total_investment(), contract.total_stake);
+
+        let history = contract.get_deposit_history(account("alice.testnet"), None, None);
+        assert_eq!(
+            history,
+            vec![
+                DepositRecord {
+                    ledger: Ledger::Investor,
+                    token_id: None,
+                    amount: U128(3 * MIN_DEPOSIT),
+                    block_timestamp: U64(100),
+                    epoch_height: 1,
+                },
+                DepositRecord {
+                    ledger: Ledger::Investor,
+                    token_id: None,
+                    amount: U128(2 * MIN_DEPOSIT),
+                    block_timestamp: U64(200),
+                    epoch_height: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_play_top_up_accumulates() {
+        let mut contract = setup();
+        set_context_at("bob.testnet", MIN_DEPOSIT, 100, 1);
+        contract.play(U128(MIN_DEPOSIT));
+        set_context_at("bob.testnet", 4 * MIN_DEPOSIT, 300, 3);
+        contract.play(U128(4 * MIN_DEPOSIT));
+
+        assert_eq!(
+            contract.players.get(&account("bob.testnet")),
+            Some(5 * MIN_DEPOSIT)
+        );
+        assert_eq!(contract.total_player_deposit(), 5 * MIN_DEPOSIT);
+
+        let history = contract.get_deposit_history(account("bob.testnet"), Some(1), Some(10));
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].ledger, Ledger::Player);
+        assert_eq!(history[0].amount, U128(4 * MIN_DEPOSIT));
+        assert_eq!(history[0].block_timestamp, U64(300));
+        assert_eq!(history[0].epoch_height, 3);
+    }
+
+    #[test]
+    fn test_deposit_history_is_per_account() {
+        let mut contract = setup();
+        set_context("alice.testnet", MIN_DEPOSIT);
+        contract.invest(U128(MIN_DEPOSIT));
+        set_context("bob.testnet", MIN_DEPOSIT);
+        contract.play(U128(MIN_DEPOSIT));
+
+        assert_eq!(
+            contract
+                .get_deposit_history(account("alice.testnet"), None, None)
+                .len(),
+            1
+        );
+        assert_eq!(
+            contract
+                .get_deposit_history(account("bob.testnet"), None, None)
+                .len(),
+            1
+        );
+        assert!(contract
+            .get_deposit_history(account("carol.testnet"), None, None)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_player_withdraw_debits_and_transfers() {
+        let mut contract = setup();
+        set_context("bob.testnet", 5 * MIN_DEPOSIT);
+        contract.play(U128(5 * MIN_DEPOSIT));
+
+        set_context("bob.testnet", 0);
+        contract.instant_withdraw(U128(MIN_DEPOSIT / 2));
+        assert_eq!(
+            contract.players.get(&account("bob.testnet")),
+            Some(9 * MIN_DEPOSIT / 2)
+        );
+        let receipts = get_created_receipts();
+        assert_eq!(receipts[0].receiver_id, account("bob.testnet"));
+
+        contract.withdraw_all(Ledger::Player);
+        assert_eq!(contract.players.get(&account("bob.testnet")), None);
+        assert_eq!(contract.players.len(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Withdrawal exceeds balance")]
+    fn test_withdraw_more_than_balance() {
+        let mut contract = setup();
+        set_context("bob.testnet", MIN_DEPOSIT);
+        contract.play(U128(MIN_DEPOSIT));
+
+        set_context("bob.testnet", 0);
+        contract.withdraw(Ledger::Player, U128(MIN_DEPOSIT + 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Nothing to withdraw")]
+    fn test_withdraw_all_without_balance() {
+        let mut contract = setup();
+        set_context("bob.testnet", 0);
+        contract.withdraw_all(Ledger::Investor);
+    }
+
+    #[test]
+    fn test_investor_withdraw_waits_for_unstake_delay() {
+        let mut contract = setup();
+        set_context_at("alice.testnet", 5 * MIN_DEPOSIT, 0, 9);
+        contract.invest(U128(5 * MIN_DEPOSIT));
+        contract.sync_stake();
+
+        set_context_at("alice.testnet", 0, 0, 10);
+        contract.withdraw(Ledger::Investor, U128(2 * MIN_DEPOSIT));
+        assert_eq!(
+            contract.investors.get(&account("alice.testnet")),
+            Some(3 * MIN_DEPOSIT)
+        );
+        assert_eq!(contract.total_stake, 3 * MIN_DEPOSIT);
+        contract.sync_stake();
+        assert_eq!(
+            contract.get_pending_withdrawals(account("alice.testnet")),
+            vec![PendingWithdrawal {
+                amount: U128(2 * MIN_DEPOSIT),
+                available_epoch: 10 + UNSTAKE_DELAY_EPOCHS,
+                unstaked: true,
+            }]
+        );
+
+        set_context_at("alice.testnet", 0, 0, 10 + UNSTAKE_DELAY_EPOCHS);
+        contract.complete_withdrawal();
+        assert!(contract
+            .get_pending_withdrawals(account("alice.testnet"))
+            .is_empty());
+        let receipts = get_created_receipts();
+        assert_eq!(receipts[0].receiver_id, account("staking.testnet"));
+        assert_eq!(receipts[1].receiver_id, account(CONTRACT_ID));
+    }
+
+    #[test]
+    #[should_panic(expected = "Withdrawal is not available until epoch 14")]
+    fn test_complete_withdrawal_before_delay() {
+        let mut contract = setup();
+        set_context_at("alice.testnet", MIN_DEPOSIT, 0, 9);
+        contract.invest(U128(MIN_DEPOSIT));
+        contract.sync_stake();
+        set_context_at("alice.testnet", 0, 0, 10);
+        contract.withdraw_all(Ledger::Investor);
+        contract.sync_stake();
+
+        set_context_at("alice.testnet", 0, 0, 13);
+        contract.complete_withdrawal();
+    }
+
+    /// Bob plays 3 NEAR, 0.3 of which the buffer keeps, and instantly
+    /// withdraws a quarter of a NEAR. Returns the payout and the fee.
+    fn setup_instant_withdrawal(contract: &mut Contract) -> (U128, U128) {
+        set_context("bob.testnet", 3 * MIN_DEPOSIT);
+        contract.play(U128(3 * MIN_DEPOSIT));
+        set_context("bob.testnet", 0);
+        let paid = contract.instant_withdraw(U128(MIN_DEPOSIT / 4));
+        (paid, U128(MIN_DEPOSIT / 4 - paid.0))
+    }
+
+    #[test]
+    fn test_failed_player_transfer_restores_balance() {
+        let mut contract = setup();
+        let (paid, fee) = setup_instant_withdrawal(&mut contract);
+        assert_eq!(contract.liquid_buffer, 3 * MIN_DEPOSIT / 10 - paid.0);
+
+        set_callback_context(vec![PromiseResult::Failed]);
+        contract.on_instant_withdraw_transfer(account("bob.testnet"), paid, fee);
+        assert_eq!(
+            contract.players.get(&account("bob.testnet")),
+            Some(3 * MIN_DEPOSIT)
+        );
+        assert_eq!(contract.liquid_buffer, 3 * MIN_DEPOSIT / 10);
+        assert_eq!(contract.total_stake, 27 * MIN_DEPOSIT / 10);
+        // Nothing was queued, so nothing is owed twice.
+        assert!(contract
+            .get_pending_withdrawals(account("bob.testnet"))
+            .is_empty());
+        assert_eq!(contract.total_unstaking, 0);
+    }
+
+    #[test]
+    fn test_successful_player_transfer_keeps_debit() {
+        let mut contract = setup();
+        let (paid, fee) = setup_instant_withdrawal(&mut contract);
+
+        set_callback_context(vec![PromiseResult::Successful(vec![])]);
+        contract.on_instant_withdraw_transfer(account("bob.testnet"), paid, fee);
+        assert_eq!(
+            contract.players.get(&account("bob.testnet")),
+            Some(11 * MIN_DEPOSIT / 4)
+        );
+        assert_eq!(contract.liquid_buffer, 3 * MIN_DEPOSIT / 10 - paid.0);
+        assert_eq!(contract.total_stake, 27 * MIN_DEPOSIT / 10 - fee.0);
+        assert!(contract
+            .get_pending_withdrawals(account("bob.testnet"))
+            .is_empty());
+        assert!(contract.get_unstake_queue().is_empty());
+    }
+
+    #[test]
+    fn test_failed_investor_transfer_restores_pending_withdrawal() {
+        let mut contract = setup();
+        set_callback_context(vec![PromiseResult::Failed]);
+        contract.on_withdraw_transfer(
+            account("alice.testnet"),
+            Ledger::Investor,
+            U128(MIN_DEPOSIT),
+        );
+        assert_eq!(
+            contract.get_pending_withdrawals(account("alice.testnet")),
+            vec![PendingWithdrawal {
+                amount: U128(MIN_DEPOSIT),
+                available_epoch: 0,
+                unstaked: true,
+            }]
+        );
+    }
+}
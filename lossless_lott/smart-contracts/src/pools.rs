@@ -0,0 +1,850 @@
+//! The validators' staking pools the contract spreads its stake over, so an
+//! outage or slashing at one validator only touches that pool's share. New
+//! stake is split by the pools' target weights. A pool the owner removes is
+//! drained: the next `sync_stake` unstakes the staked balance the pool
+//! reports, and once the pool releases it `complete_drain` moves it back into
+//! `pending_stake` to be staked in the remaining pools. The pool is dropped
+//! once a yield measurement finds nothing left in it. A pool restarts its unstake delay for all
+//! of the contract's unstaked funds on every unstake, so a pool takes no new
+//! unstake until what it holds unstaked has been withdrawn.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json;
+use near_sdk::{env, near_bindgen, AccountId, Balance, Gas, Promise, PromiseError, PromiseResult};
+
+use crate::staking::{
+    assert_enough_gas, ext_staking_contract, PoolBalance, GAS_FOR_VIEW, GAS_FOR_WITHDRAW, TGAS,
+};
+use crate::vault::mul_div;
+use crate::{is_promise_success, Contract, ContractExt};
+
+/// Upper bound on staking pools, keeping a sync's call to every pool within
+/// one transaction's gas.
+pub const MAX_STAKING_POOLS: usize = 4;
+
+const GAS_FOR_ON_DRAIN_WITHDRAW: Gas = Gas(10 * TGAS);
+const GAS_FOR_ON_DRAIN_AVAILABLE: Gas =
+    Gas(10 * TGAS + GAS_FOR_WITHDRAW.0 + GAS_FOR_ON_DRAIN_WITHDRAW.0);
+
+/// A staking pool and what the contract holds in it.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakingPool {
+    pub account_id: AccountId,
+    /// Target share of new stake, relative to the other pools' weights.
+    pub weight: u32,
+    pub staked: U128,
+    /// Unstaked for withdrawals and not yet withdrawn.
+    pub unstaked: U128,
+    /// Unstaked to drain a removed pool and not yet withdrawn.
+    pub draining: U128,
+    /// Part of `draining` a `complete_drain` is withdrawing right now.
+    pub drain_in_flight: U128,
+    /// Epoch the pool releases its unstaked funds in, as of the last
+    /// unstake.
+    pub unlock_epoch: u64,
+    /// Takes no new stake and is dropped once drained.
+    pub removed: bool,
+    /// Everything ever staked in the pool and withdrawn from it.
+    pub total_deposited: U128,
+    pub total_withdrawn: U128,
+    /// Total balance the pool reported at the last yield measurement.
+    pub total_balance: U128,
+    /// What the pool had earned for the contract as of `total_balance`.
+    pub earned: U128,
+    /// Last answer to `refresh_pool_balance`.
+    pub balance: PoolBalance,
+}
+
+impl StakingPool {
+    pub fn new(account_id: AccountId, weight: u32) -> Self {
+        Self {
+            account_id,
+            weight,
+            staked: U128(0),
+            unstaked: U128(0),
+            draining: U128(0),
+            drain_in_flight: U128(0),
+            unlock_epoch: 0,
+            removed: false,
+            total_deposited: U128(0),
+            total_withdrawn: U128(0),
+            total_balance: U128(0),
+            earned: U128(0),
+            balance: PoolBalance::default(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.staked.0 == 0 && !self.is_unstaking()
+    }
+
+    /// What the pool holds staked for the contract, going by its last
+    /// measurement and the stake moved since.
+    fn holdings(&self) -> Balance {
+        (self.total_deposited.0 + self.earned.0).saturating_sub(
+            self.total_withdrawn.0 + self.unstaked.0 + self.draining.0 + self.drain_in_flight.0,
+        )
+    }
+
+    /// Still holds unstaked funds, which another unstake would lock again.
+    fn is_unstaking(&self) -> bool {
+        self.unstaked.0 > 0 || self.draining.0 > 0 || self.drain_in_flight.0 > 0
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Starts staking in another pool, which takes `weight` out of the
+    /// pools' total weight of new stake. Re-adding a removed pool stops its
+    /// drain.
+    pub fn add_staking_pool(&mut self, account_id: AccountId, weight: u32) {
+        self.assert_owner();
+        assert!(weight > 0, "Staking pool weight must be positive");
+        if let Some(pool) = self.find_staking_pool(&account_id) {
+            assert!(
+                pool.removed,
+                "Staking pool {} is already in use",
+                account_id
+            );
+            pool.removed = false;
+            pool.weight = weight;
+            return;
+        }
+        assert!(
+            self.staking_pools.len() < MAX_STAKING_POOLS,
+            "At most {} staking pools can be used",
+            MAX_STAKING_POOLS
+        );
+        self.staking_pools
+            .push(StakingPool::new(account_id, weight));
+    }
+
+    /// Changes a pool's share of new stake. Stake already in the pools is
+    /// not moved.
+    pub fn set_staking_pool_weight(&mut self, account_id: AccountId, weight: u32) {
+        self.assert_owner();
+        assert!(weight > 0, "Staking pool weight must be positive");
+        self.active_staking_pool(&account_id).weight = weight;
+    }
+
+    /// Stops staking in a pool and drains it, starting at the next
+    /// `sync_stake`.
+    pub fn remove_staking_pool(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        assert!(
+            self.staking_pools
+                .iter()
+                .any(|pool| !pool.removed && pool.account_id != account_id),
+            "Cannot remove the last staking pool"
+        );
+        let pool = self.active_staking_pool(&account_id);
+        pool.removed = true;
+        pool.weight = 0;
+        env::log_str(&format!(
+            "Removed staking pool {}, draining {}",
+            account_id, pool.staked.0
+        ));
+        // A pool never staked in has nothing to measure.
+        if pool.total_deposited.0 == 0 {
+            self.staking_pools
+                .retain(|pool| pool.account_id != account_id);
+        }
+    }
+
+    /// Withdraws what a removed pool has released and queues it to be
+    /// staked in the remaining pools. Anyone may call it; the amount is
+    /// held in flight until the withdrawal resolves, so a second call finds
+    /// nothing to drain.
+    pub fn complete_drain(&mut self, account_id: AccountId) -> Promise {
+        assert_enough_gas(Gas(GAS_FOR_VIEW.0 + GAS_FOR_ON_DRAIN_AVAILABLE.0));
+        let pool = self.find_staking_pool(&account_id);
+        let amount = pool.as_ref().map_or(0, |pool| pool.draining.0);
+        assert!(amount > 0, "Nothing to drain from {}", account_id);
+        let pool = pool.unwrap();
+        pool.draining.0 = 0;
+        pool.drain_in_flight.0 += amount;
+        ext_staking_contract::ext(account_id.clone())
+            .with_static_gas(GAS_FOR_VIEW)
+            .is_account_unstaked_balance_available(env::current_account_id())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_DRAIN_AVAILABLE)
+                    .on_drain_available(account_id, U128(amount)),
+            )
+    }
+
+    #[private]
+    pub fn on_drain_available(
+        &mut self,
+        pool_id: AccountId,
+        amount: U128,
+        #[callback_result] available: Result<bool, PromiseError>,
+    ) {
+        if available != Ok(true) {
+            env::log_str(&format!(
+                "Drained balance is not yet available in {}",
+                pool_id
+            ));
+            self.return_drain(&pool_id, amount.0);
+            return;
+        }
+        ext_staking_contract::ext(pool_id.clone())
+            .with_static_gas(GAS_FOR_WITHDRAW)
+            .withdraw(amount)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_DRAIN_WITHDRAW)
+                    .on_drain_withdraw(pool_id, amount),
+            );
+    }
+
+    #[private]
+    pub fn on_drain_withdraw(&mut self, pool_id: AccountId, amount: U128) -> bool {
+        if !is_promise_success() {
+            self.return_drain(&pool_id, amount.0);
+            return false;
+        }
+        let pool = self
+            .find_staking_pool(&pool_id)
+            .expect("Drained staking pool is missing");
+        pool.drain_in_flight.0 -= amount.0;
+        pool.total_withdrawn.0 += amount.0;
+        self.pending_stake += amount.0;
+        env::log_str(&format!("Drained {} from {}", amount.0, pool_id));
+        true
+    }
+
+    pub fn get_staking_pools(&self) -> Vec<StakingPool> {
+        self.staking_pools.clone()
+    }
+}
+
+impl Contract {
+    pub(crate) fn find_staking_pool(&mut self, account_id: &AccountId) -> Option<&mut StakingPool> {
+        self.staking_pools
+            .iter_mut()
+            .find(|pool| &pool.account_id == account_id)
+    }
+
+    fn active_staking_pool(&mut self, account_id: &AccountId) -> &mut StakingPool {
+        match self.find_staking_pool(account_id) {
+            Some(pool) if !pool.removed => pool,
+            _ => panic!("Unknown staking pool {}", account_id),
+        }
+    }
+
+    /// Puts a drain that did not go through back to be retried.
+    fn return_drain(&mut self, pool_id: &AccountId, amount: Balance) {
+        let pool = self
+            .find_staking_pool(pool_id)
+            .expect("Drained staking pool is missing");
+        pool.drain_in_flight.0 -= amount;
+        pool.draining.0 += amount;
+    }
+
+    /// Splits `amount` of new stake between the active pools by weight.
+    pub(crate) fn split_stake(&self, amount: Balance) -> Vec<(AccountId, Balance)> {
+        let active: Vec<&StakingPool> = self.staking_pools.iter().filter(|p| !p.removed).collect();
+        let total_weight: u32 = active.iter().map(|pool| pool.weight).sum();
+        let mut parts: Vec<(AccountId, Balance)> = active
+            .iter()
+            .map(|pool| {
+                let part = mul_div(amount, pool.weight as u128, total_weight as u128, false);
+                (pool.account_id.clone(), part)
+            })
+            .collect();
+        // Rounding dust goes to the heaviest pool.
+        let split: Balance = parts.iter().map(|(_, part)| part).sum();
+        let heaviest = (0..active.len()).max_by_key(|&i| active[i].weight).unwrap();
+        parts[heaviest].1 += amount - split;
+        parts.retain(|(_, part)| *part > 0);
+        parts
+    }
+
+    /// Plans the unstakes for `amount` of withdrawals plus the drain of
+    /// every removed pool, as `(pool, withdrawn, drained)`, along with the
+    /// part of `amount` no pool can take. Removed pools cover withdrawals
+    /// first; the active pools give up the rest in proportion to their
+    /// stake, so their weights are kept, each giving up no more than it
+    /// holds. Pools still holding unstaked funds are left out. A removed
+    /// pool is always listed, since it may hold rewards it was never
+    /// measured with; `on_drain_balance` unstakes what it really holds.
+    pub(crate) fn split_unstake(
+        &self,
+        amount: Balance,
+    ) -> (Vec<(AccountId, Balance, Balance)>, Balance) {
+        let mut remaining = amount;
+        let mut parts = vec![];
+        let open: Vec<&StakingPool> = self
+            .staking_pools
+            .iter()
+            .filter(|p| !p.is_unstaking())
+            .collect();
+        for pool in open.iter().filter(|p| p.removed) {
+            let holdings = pool.holdings();
+            let withdrawn = std::cmp::min(remaining, holdings);
+            remaining -= withdrawn;
+            parts.push((pool.account_id.clone(), withdrawn, holdings - withdrawn));
+        }
+
+        let active: Vec<&StakingPool> = open.into_iter().filter(|p| !p.removed).collect();
+        let total_staked: Balance = active.iter().map(|pool| pool.staked.0).sum();
+        let mut shares: Vec<Balance> = active
+            .iter()
+            .map(|pool| match total_staked {
+                0 => 0,
+                _ => std::cmp::min(
+                    mul_div(remaining, pool.staked.0, total_staked, false),
+                    pool.holdings(),
+                ),
+            })
+            .collect();
+        // Rounding dust, yield unstaked beyond the principal and whatever a
+        // pool cannot cover go to the pools with the most room left.
+        let mut left = remaining - shares.iter().sum::<Balance>();
+        let mut order: Vec<usize> = (0..active.len()).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(active[i].holdings() - shares[i]));
+        for i in order {
+            let more = std::cmp::min(left, active[i].holdings() - shares[i]);
+            shares[i] += more;
+            left -= more;
+        }
+        parts.extend(
+            active
+                .iter()
+                .zip(shares)
+                .filter(|(_, share)| *share > 0)
+                .map(|(pool, share)| (pool.account_id.clone(), share, 0)),
+        );
+        (parts, left)
+    }
+
+    /// Latest epoch a pool holding unstaked withdrawals releases them in.
+    pub(crate) fn unstaked_unlock_epoch(&self) -> u64 {
+        self.staking_pools
+            .iter()
+            .filter(|pool| pool.unstaked.0 > 0)
+            .map(|pool| pool.unlock_epoch)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Picks the pools to withdraw `amount` of unstaked funds from, out of
+    /// those that have released them, along with the part of `amount` they
+    /// do not hold.
+    pub(crate) fn split_withdrawal(&self, amount: Balance) -> (Vec<(AccountId, Balance)>, Balance) {
+        let epoch = env::epoch_height();
+        let mut remaining = amount;
+        let mut parts: Vec<(AccountId, Balance)> = self
+            .staking_pools
+            .iter()
+            .map(|pool| {
+                let unlocked = match pool.unlock_epoch <= epoch {
+                    true => pool.unstaked.0,
+                    false => 0,
+                };
+                let part = std::cmp::min(remaining, unlocked);
+                remaining -= part;
+                (pool.account_id.clone(), part)
+            })
+            .collect();
+        parts.retain(|(_, part)| *part > 0);
+        (parts, remaining)
+    }
+
+    /// Asks every pool for the contract's total balance, to be read back by
+    /// `record_pool_totals` in the callback.
+    pub(crate) fn query_pool_totals(&self) -> Promise {
+        self.staking_pools
+            .iter()
+            .map(|pool| {
+                ext_staking_contract::ext(pool.account_id.clone())
+                    .with_static_gas(GAS_FOR_VIEW)
+                    .get_account_total_balance(env::current_account_id())
+            })
+            .reduce(|all, query| all.and(query))
+            .expect("No staking pools")
+    }
+
+    pub(crate) fn staking_pool_ids(&self) -> Vec<AccountId> {
+        self.staking_pools
+            .iter()
+            .map(|pool| pool.account_id.clone())
+            .collect()
+    }
+
+    /// Records what each of `pool_ids` reported from `query_pool_totals`
+    /// and returns their sum, or nothing if any pool could not be read. A
+    /// removed pool found empty is dropped.
+    pub(crate) fn record_pool_totals(&mut self, pool_ids: &[AccountId]) -> Option<Balance> {
+        assert_eq!(
+            env::promise_results_count(),
+            pool_ids.len() as u64,
+            "Expected one promise result per staking pool"
+        );
+        let mut totals = vec![];
+        for index in 0..pool_ids.len() {
+            match env::promise_result(index as u64) {
+                PromiseResult::Successful(value) => {
+                    totals.push(serde_json::from_slice::<U128>(&value).ok()?.0)
+                }
+                _ => return None,
+            }
+        }
+        for (pool_id, total) in pool_ids.iter().zip(&totals) {
+            if let Some(pool) = self.find_staking_pool(pool_id) {
+                pool.total_balance = U128(*total);
+                pool.earned =
+                    U128((total + pool.total_withdrawn.0).saturating_sub(pool.total_deposited.0));
+            }
+        }
+        self.staking_pools.retain(|pool| {
+            !(pool.removed
+                && pool.is_empty()
+                && pool_ids.contains(&pool.account_id)
+                && pool.total_balance.0 == 0)
+        });
+        Some(totals.iter().sum())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::Ledger;
+
+    fn set_owner_context() {
+        set_context("owner.testnet", 0);
+    }
+
+    /// Pools "a" and "b" take new stake 3:1.
+    fn setup_pools() -> Contract {
+        let mut contract = setup();
+        set_owner_context();
+        contract.add_staking_pool(account("a.testnet"), 3);
+        contract.add_staking_pool(account("b.testnet"), 1);
+        contract.remove_staking_pool(account("staking.testnet"));
+        contract
+    }
+
+    fn invest(contract: &mut Contract, name: &str, amount: Balance) {
+        set_context(name, amount);
+        contract.invest(U128(amount));
+    }
+
+    fn staked(contract: &Contract) -> Vec<(AccountId, Balance)> {
+        contract
+            .get_staking_pools()
+            .into_iter()
+            .map(|pool| (pool.account_id, pool.staked.0))
+            .collect()
+    }
+
+    #[test]
+    fn test_new_stake_is_split_by_weight() {
+        let mut contract = setup_pools();
+        invest(&mut contract, "alice.testnet", 8 * MIN_DEPOSIT);
+        contract.sync_stake();
+
+        let calls = function_calls();
+        assert_eq!(
+            calls[0],
+            (
+                account("a.testnet"),
+                "deposit_and_stake".to_string(),
+                6 * MIN_DEPOSIT
+            )
+        );
+        assert_eq!(
+            calls[2],
+            (
+                account("b.testnet"),
+                "deposit_and_stake".to_string(),
+                2 * MIN_DEPOSIT
+            )
+        );
+        assert_eq!(
+            staked(&contract),
+            vec![
+                (account("a.testnet"), 6 * MIN_DEPOSIT),
+                (account("b.testnet"), 2 * MIN_DEPOSIT),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unstake_keeps_the_pools_in_proportion() {
+        let mut contract = setup_pools();
+        invest(&mut contract, "alice.testnet", 8 * MIN_DEPOSIT);
+        contract.sync_stake();
+        set_context_at("alice.testnet", 0, 0, 1);
+        contract.withdraw(Ledger::Investor, U128(4 * MIN_DEPOSIT));
+        contract.sync_stake();
+
+        let calls = function_calls();
+        assert_eq!(calls[0], (account("a.testnet"), "unstake".to_string(), 0));
+        assert_eq!(calls[2], (account("b.testnet"), "unstake".to_string(), 0));
+        assert_eq!(
+            staked(&contract),
+            vec![
+                (account("a.testnet"), 3 * MIN_DEPOSIT),
+                (account("b.testnet"), MIN_DEPOSIT),
+            ]
+        );
+        assert_eq!(
+            contract.get_staking_pools()[0].unstaked,
+            U128(3 * MIN_DEPOSIT)
+        );
+    }
+
+    #[test]
+    fn test_completed_withdrawal_draws_on_every_pool_holding_it() {
+        let mut contract = setup_pools();
+        invest(&mut contract, "alice.testnet", 8 * MIN_DEPOSIT);
+        contract.sync_stake();
+        set_context_at("alice.testnet", 0, 0, 1);
+        contract.withdraw(Ledger::Investor, U128(4 * MIN_DEPOSIT));
+        contract.sync_stake();
+
+        set_context_at("alice.testnet", 0, 0, 1 + crate::UNSTAKE_DELAY_EPOCHS);
+        contract.complete_withdrawal();
+        let calls = function_calls();
+        assert_eq!(calls[0].0, account("a.testnet"));
+        assert_eq!(calls[2].0, account("b.testnet"));
+
+        set_callback_context(vec![PromiseResult::Successful(vec![])]);
+        contract.on_pool_withdraw(
+            account("alice.testnet"),
+            Ledger::Investor,
+            account("b.testnet"),
+            U128(MIN_DEPOSIT),
+        );
+        let pool = &contract.get_staking_pools()[1];
+        assert_eq!(pool.unstaked, U128(0));
+        assert_eq!(pool.total_withdrawn, U128(MIN_DEPOSIT));
+    }
+
+    #[test]
+    fn test_pool_holding_unstaked_funds_takes_no_new_unstake() {
+        let mut contract = setup_pools();
+        invest(&mut contract, "alice.testnet", 8 * MIN_DEPOSIT);
+        contract.sync_stake();
+        set_context_at("alice.testnet", 0, 0, 1);
+        contract.withdraw(Ledger::Investor, U128(4 * MIN_DEPOSIT));
+        contract.sync_stake();
+        set_callback_context(vec![PromiseResult::Successful(vec![])]);
+        contract.on_pool_withdraw(
+            account("alice.testnet"),
+            Ledger::Investor,
+            account("b.testnet"),
+            U128(MIN_DEPOSIT),
+        );
+
+        // "a" still holds 3 NEAR unstaked, so "b" covers the next batch.
+        set_context_at("alice.testnet", 0, 0, 2);
+        contract.withdraw(Ledger::Investor, U128(MIN_DEPOSIT));
+        contract.sync_stake();
+        let calls = function_calls();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0], (account("b.testnet"), "unstake".to_string(), 0));
+        let pools = contract.get_staking_pools();
+        assert_eq!(pools[0].unlock_epoch, 1 + crate::UNSTAKE_DELAY_EPOCHS);
+        assert_eq!(pools[1].unlock_epoch, 2 + crate::UNSTAKE_DELAY_EPOCHS);
+        assert_eq!(
+            staked(&contract),
+            vec![
+                (account("a.testnet"), 3 * MIN_DEPOSIT),
+                (account("b.testnet"), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_removed_pool_is_drained_into_the_others() {
+        let mut contract = setup_pools();
+        invest(&mut contract, "alice.testnet", 8 * MIN_DEPOSIT);
+        contract.sync_stake();
+        set_owner_context();
+        contract.remove_staking_pool(account("b.testnet"));
+
+        // The next sync unstakes all of it, even with nothing to withdraw,
+        // going by what the pool reports rather than what was staked.
+        set_context_at("keeper.testnet", 0, 0, 1);
+        contract.sync_stake();
+        let calls = function_calls();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(
+            calls[0],
+            (
+                account("b.testnet"),
+                "get_account_staked_balance".to_string(),
+                0
+            )
+        );
+        set_callback_context(pool_result(5 * MIN_DEPOSIT / 2));
+        contract.on_drain_balance(
+            account("b.testnet"),
+            U128(0),
+            U128(2 * MIN_DEPOSIT),
+            vec![],
+            Ok(U128(5 * MIN_DEPOSIT / 2)),
+        );
+        assert_eq!(function_call_args("unstake")["amount"], "2500000");
+        let pool = &contract.get_staking_pools()[1];
+        assert_eq!(
+            (pool.staked, pool.draining),
+            (U128(0), U128(5 * MIN_DEPOSIT / 2))
+        );
+        // Still owed, so still counted in the pools.
+        assert_eq!(contract.principal_in_pool(), U128(8 * MIN_DEPOSIT));
+
+        set_context_at("keeper.testnet", 0, 0, 1 + crate::UNSTAKE_DELAY_EPOCHS);
+        contract.complete_drain(account("b.testnet"));
+        assert_eq!(
+            function_calls()[0].1,
+            "is_account_unstaked_balance_available"
+        );
+        set_callback_context(vec![PromiseResult::Successful(vec![])]);
+        assert!(contract.on_drain_withdraw(account("b.testnet"), U128(5 * MIN_DEPOSIT / 2)));
+        assert_eq!(contract.pending_stake, 5 * MIN_DEPOSIT / 2);
+
+        // Dropped only once a measurement finds it empty.
+        assert_eq!(contract.get_staking_pools().len(), 2);
+        set_callback_context(vec![
+            pool_result(6 * MIN_DEPOSIT).remove(0),
+            pool_result(0).remove(0),
+        ]);
+        contract.on_measure_yield(vec![account("a.testnet"), account("b.testnet")]);
+        assert_eq!(
+            staked(&contract),
+            vec![(account("a.testnet"), 6 * MIN_DEPOSIT)]
+        );
+
+        set_context_at("keeper.testnet", 0, 0, 2 + crate::UNSTAKE_DELAY_EPOCHS);
+        contract.sync_stake();
+        assert_eq!(
+            function_calls()[0],
+            (
+                account("a.testnet"),
+                "deposit_and_stake".to_string(),
+                5 * MIN_DEPOSIT / 2
+            )
+        );
+    }
+
+    #[test]
+    fn test_removed_pool_holding_less_leaves_withdrawals_to_the_others() {
+        let mut contract = setup_pools();
+        invest(&mut contract, "alice.testnet", 8 * MIN_DEPOSIT);
+        contract.sync_stake();
+        set_owner_context();
+        contract.remove_staking_pool(account("b.testnet"));
+        set_context_at("alice.testnet", 0, 0, 1);
+        contract.withdraw(Ledger::Investor, U128(2 * MIN_DEPOSIT));
+        contract.sync_stake();
+
+        set_callback_context(pool_result(MIN_DEPOSIT));
+        contract.on_drain_balance(
+            account("b.testnet"),
+            U128(2 * MIN_DEPOSIT),
+            U128(0),
+            vec![1],
+            Ok(U128(MIN_DEPOSIT)),
+        );
+        assert_eq!(function_call_args("unstake")["amount"], "1000000");
+        assert_eq!(contract.unstake_shortfall, MIN_DEPOSIT);
+        assert_eq!(
+            contract.get_withdrawal_batch(1).unwrap().unstaked_epoch,
+            None
+        );
+        let pool = &contract.get_staking_pools()[1];
+        assert_eq!((pool.unstaked, pool.draining), (U128(MIN_DEPOSIT), U128(0)));
+    }
+
+    #[test]
+    fn test_unstake_share_is_capped_at_what_the_pool_holds() {
+        let mut contract = setup_pools();
+        invest(&mut contract, "alice.testnet", 8 * MIN_DEPOSIT);
+        contract.sync_stake();
+        set_callback_context(vec![
+            pool_result(9 * MIN_DEPOSIT).remove(0),
+            pool_result(2 * MIN_DEPOSIT).remove(0),
+        ]);
+        contract.on_measure_yield(vec![account("a.testnet"), account("b.testnet")]);
+
+        // "b" would owe 2.5 NEAR by stake but holds 2; "a" takes the rest.
+        assert_eq!(
+            contract.split_unstake(10 * MIN_DEPOSIT),
+            (
+                vec![
+                    (account("a.testnet"), 8 * MIN_DEPOSIT, 0),
+                    (account("b.testnet"), 2 * MIN_DEPOSIT, 0),
+                ],
+                0
+            )
+        );
+        assert_eq!(contract.split_unstake(12 * MIN_DEPOSIT).1, MIN_DEPOSIT);
+    }
+
+    #[test]
+    fn test_withdrawal_beyond_the_pools_is_put_back() {
+        let mut contract = setup_pools();
+        invest(&mut contract, "alice.testnet", 8 * MIN_DEPOSIT);
+        contract.sync_stake();
+        set_context_at("alice.testnet", 0, 0, 1);
+        contract.withdraw(Ledger::Investor, U128(4 * MIN_DEPOSIT));
+        contract.sync_stake();
+
+        set_context_at("alice.testnet", 0, 0, 1 + crate::UNSTAKE_DELAY_EPOCHS);
+        contract.withdraw_unstaked(account("alice.testnet"), Ledger::Investor, 5 * MIN_DEPOSIT);
+        assert_eq!(function_calls().len(), 4);
+        let pending = contract.get_pending_withdrawals(account("alice.testnet"));
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[1].amount, U128(MIN_DEPOSIT));
+        assert!(pending[1].unstaked);
+    }
+
+    /// Pool "b" is removed with 2 NEAR in it, unstaked for the drain at
+    /// epoch 1 and released by the epoch returned.
+    fn setup_drained() -> (Contract, u64) {
+        let mut contract = setup_pools();
+        invest(&mut contract, "alice.testnet", 8 * MIN_DEPOSIT);
+        contract.sync_stake();
+        set_owner_context();
+        contract.remove_staking_pool(account("b.testnet"));
+        set_context_at("keeper.testnet", 0, 0, 1);
+        contract.sync_stake();
+        set_callback_context(pool_result(2 * MIN_DEPOSIT));
+        contract.on_drain_balance(
+            account("b.testnet"),
+            U128(0),
+            U128(2 * MIN_DEPOSIT),
+            vec![],
+            Ok(U128(2 * MIN_DEPOSIT)),
+        );
+        (contract, 1 + crate::UNSTAKE_DELAY_EPOCHS)
+    }
+
+    #[test]
+    #[should_panic(expected = "Nothing to drain from b.testnet")]
+    fn test_drain_cannot_run_twice_at_once() {
+        let (mut contract, epoch) = setup_drained();
+        set_context_at("keeper.testnet", 0, 0, epoch);
+        contract.complete_drain(account("b.testnet"));
+        let pool = &contract.get_staking_pools()[1];
+        assert_eq!(pool.draining, U128(0));
+        assert_eq!(pool.drain_in_flight, U128(2 * MIN_DEPOSIT));
+
+        set_context_at("mallory.testnet", 0, 0, epoch);
+        contract.complete_drain(account("b.testnet"));
+    }
+
+    #[test]
+    fn test_drain_not_yet_released_can_be_retried() {
+        let (mut contract, epoch) = setup_drained();
+        set_context_at("keeper.testnet", 0, 0, epoch);
+        contract.complete_drain(account("b.testnet"));
+
+        set_callback_context(vec![PromiseResult::Successful(b"false".to_vec())]);
+        contract.on_drain_available(account("b.testnet"), U128(2 * MIN_DEPOSIT), Ok(false));
+        let pool = &contract.get_staking_pools()[1];
+        assert_eq!(pool.draining, U128(2 * MIN_DEPOSIT));
+        assert_eq!(pool.drain_in_flight, U128(0));
+        assert_eq!(contract.pending_stake, 0);
+
+        set_context_at("keeper.testnet", 0, 0, epoch);
+        contract.complete_drain(account("b.testnet"));
+        set_callback_context(vec![PromiseResult::Failed]);
+        assert!(!contract.on_drain_withdraw(account("b.testnet"), U128(2 * MIN_DEPOSIT)));
+        assert_eq!(
+            contract.get_staking_pools()[1].draining,
+            U128(2 * MIN_DEPOSIT)
+        );
+        assert_eq!(contract.pending_stake, 0);
+    }
+
+    #[test]
+    fn test_draining_pool_covers_withdrawals_first() {
+        let mut contract = setup_pools();
+        invest(&mut contract, "alice.testnet", 8 * MIN_DEPOSIT);
+        contract.sync_stake();
+        set_owner_context();
+        contract.remove_staking_pool(account("b.testnet"));
+        set_context_at("alice.testnet", 0, 0, 1);
+        contract.withdraw(Ledger::Investor, U128(MIN_DEPOSIT));
+        contract.sync_stake();
+
+        assert_eq!(function_calls().len(), 2);
+        let pool = &contract.get_staking_pools()[1];
+        assert_eq!(pool.unstaked, U128(MIN_DEPOSIT));
+        assert_eq!(pool.draining, U128(MIN_DEPOSIT));
+    }
+
+    #[test]
+    fn test_measurement_tracks_yield_per_pool() {
+        let mut contract = setup_pools();
+        invest(&mut contract, "alice.testnet", 8 * MIN_DEPOSIT);
+        contract.sync_stake();
+
+        set_context("keeper.testnet", 0);
+        contract.measure_yield();
+        let calls = function_calls();
+        assert_eq!(calls[0].0, account("a.testnet"));
+        assert_eq!(calls[1].0, account("b.testnet"));
+        assert_eq!(calls[2].1, "on_measure_yield");
+
+        set_callback_context(vec![
+            pool_result(7 * MIN_DEPOSIT).remove(0),
+            pool_result(2 * MIN_DEPOSIT).remove(0),
+        ]);
+        let measurement = contract
+            .on_measure_yield(vec![account("a.testnet"), account("b.testnet")])
+            .unwrap();
+        assert_eq!(measurement.pool_total_balance, U128(9 * MIN_DEPOSIT));
+        assert_eq!(measurement.prize, U128(MIN_DEPOSIT));
+        let pools = contract.get_staking_pools();
+        assert_eq!(pools[0].earned, U128(MIN_DEPOSIT));
+        assert_eq!(pools[1].earned, U128(0));
+    }
+
+    #[test]
+    fn test_one_unreadable_pool_fails_the_measurement() {
+        let mut contract = setup_pools();
+        set_callback_context(vec![
+            pool_result(MIN_DEPOSIT).remove(0),
+            PromiseResult::Failed,
+        ]);
+        assert_eq!(
+            contract.on_measure_yield(vec![account("a.testnet"), account("b.testnet")]),
+            None
+        );
+        assert_eq!(contract.get_staking_pools()[0].total_balance, U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot remove the last staking pool")]
+    fn test_last_pool_cannot_be_removed() {
+        let mut contract = setup();
+        set_owner_context();
+        contract.remove_staking_pool(account("staking.testnet"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Staking pool a.testnet is already in use")]
+    fn test_pool_cannot_be_added_twice() {
+        let mut contract = setup_pools();
+        set_owner_context();
+        contract.add_staking_pool(account("a.testnet"), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_only_owner_adds_pools() {
+        let mut contract = setup();
+        set_context("alice.testnet", 0);
+        contract.add_staking_pool(account("a.testnet"), 1);
+    }
+}
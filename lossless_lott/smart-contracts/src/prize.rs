@@ -0,0 +1,317 @@
+//! Measuring the prize for a round from what the staking pools actually paid,
+//! and splitting it between winners by the owner's prize tiers.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId, Balance, Gas, Promise};
+
+use crate::staking::{assert_enough_gas, GAS_FOR_VIEW};
+use crate::{Contract, ContractExt};
+
+const GAS_FOR_ON_MEASURE_YIELD: Gas = Gas(10_000_000_000_000);
+
+/// Shares are expressed in basis points of the round's prize.
+pub const BASIS_POINTS: u32 = 10_000;
+
+/// Upper bound on winners per round, keeping the reveal within one call's gas.
+pub const MAX_WINNERS: u32 = 50;
+
+/// `winners` distinct players each receive `share_bps` of the prize.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PrizeTier {
+    pub winners: u32,
+    pub share_bps: u32,
+}
+
+impl PrizeTier {
+    /// The whole prize to a single winner.
+    pub fn single() -> Vec<PrizeTier> {
+        vec![PrizeTier {
+            winners: 1,
+            share_bps: BASIS_POINTS,
+        }]
+    }
+}
+
+/// Snapshot of the pool balance taken for a round. `prize` is everything the
+/// pool holds for the contract beyond the principal it owes back.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct YieldMeasurement {
+    pub round_id: u64,
+    pub pool_total_balance: U128,
+    pub principal: U128,
+    pub prize: U128,
+    pub epoch_height: u64,
+    pub block_timestamp: U64,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Queries every staking pool for the contract's total balance and
+    /// records the current round's prize. Measuring again within the same
+    /// round replaces the earlier snapshot.
+    pub fn measure_yield(&mut self) -> Promise {
+        assert_enough_gas(Gas(
+            self.staking_pools.len() as u64 * GAS_FOR_VIEW.0 + GAS_FOR_ON_MEASURE_YIELD.0
+        ));
+        self.query_pool_totals().then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_ON_MEASURE_YIELD)
+                .on_measure_yield(self.staking_pool_ids()),
+        )
+    }
+
+    #[private]
+    pub fn on_measure_yield(&mut self, pool_ids: Vec<AccountId>) -> Option<YieldMeasurement> {
+        let total_balance = match self.record_pool_totals(&pool_ids) {
+            Some(total_balance) => total_balance,
+            None => {
+                env::log_str("Could not read the total balance from the staking pools");
+                return None;
+            }
+        };
+        let measurement = self.record_yield(total_balance);
+        env::log_str(&format!(
+            "Round {} prize measured at {}",
+            measurement.round_id, measurement.prize.0
+        ));
+        Some(measurement)
+    }
+
+    /// Replaces the prize tiers used from the next draw on. Tiers are paid in
+    /// order, so the first one should hold the largest share.
+    pub fn set_prize_tiers(&mut self, tiers: Vec<PrizeTier>) {
+        self.assert_owner();
+        assert!(
+            tiers
+                .iter()
+                .all(|tier| tier.winners > 0 && tier.share_bps > 0),
+            "Prize tiers need at least one winner and a positive share"
+        );
+        let winners: u32 = tiers.iter().map(|tier| tier.winners).sum();
+        assert!(
+            winners <= MAX_WINNERS,
+            "Prize tiers may pay at most {} winners",
+            MAX_WINNERS
+        );
+        let total_bps: u64 = tiers
+            .iter()
+            .map(|tier| tier.winners as u64 * tier.share_bps as u64)
+            .sum();
+        assert_eq!(
+            total_bps, BASIS_POINTS as u64,
+            "Prize tiers must add up to {} basis points",
+            BASIS_POINTS
+        );
+        self.prize_tiers = tiers;
+    }
+
+    pub fn get_prize_tiers(&self) -> Vec<PrizeTier> {
+        self.prize_tiers.clone()
+    }
+
+    /// Yield rolled over from earlier rounds, to be won with the next prize.
+    pub fn get_jackpot(&self) -> U128 {
+        U128(self.jackpot)
+    }
+
+    pub fn get_round_yield(&self, round_id: u64) -> Option<YieldMeasurement> {
+        self.round_yields.get(&round_id)
+    }
+
+    /// Principal the staking pool holds on behalf of investors and players,
+    /// including amounts unstaked but not yet withdrawn, prizes not yet
+    /// claimed and fees and reserve already set aside by the split. Deposits
+    /// not yet staked and withdrawals the reserve covers are held by the
+    /// contract instead.
+    pub fn principal_in_pool(&self) -> U128 {
+        U128(
+            (self.total_stake
+                + self.total_unstaking
+                + self.total_prizes
+                + self.yield_buckets_total())
+            .saturating_sub(self.pending_stake + self.withdrawal_reserve),
+        )
+    }
+}
+
+impl Contract {
+    /// Records the yield implied by `pool_total_balance` for the current
+    /// round. A pool that reports less than the principal (e.g. after
+    /// slashing) yields no prize rather than a negative one.
+    pub(crate) fn record_yield(&mut self, pool_total_balance: u128) -> YieldMeasurement {
+        let principal = self.principal_in_pool().0;
+        let measurement = YieldMeasurement {
+            round_id: self.round_id,
+            pool_total_balance: U128(pool_total_balance),
+            principal: U128(principal),
+            prize: U128(pool_total_balance.saturating_sub(principal)),
+            epoch_height: env::epoch_height(),
+            block_timestamp: U64(env::block_timestamp()),
+        };
+        self.round_yields.insert(&self.round_id, &measurement);
+        measurement
+    }
+}
+
+/// Splits `prize` into one amount per place, in draw order. Rounding dust
+/// goes to the first place so a fully drawn round pays out the whole prize.
+pub fn prize_shares(prize: Balance, tiers: &[PrizeTier]) -> Vec<Balance> {
+    let mut shares: Vec<Balance> = tiers
+        .iter()
+        .flat_map(|tier| {
+            let share = prize * tier.share_bps as u128 / BASIS_POINTS as u128;
+            std::iter::repeat_n(share, tier.winners as usize)
+        })
+        .collect();
+    let paid: Balance = shares.iter().sum();
+    if let Some(first) = shares.first_mut() {
+        *first += prize - paid;
+    }
+    shares
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::Ledger;
+    use near_sdk::PromiseResult;
+
+    #[test]
+    fn test_measure_yield_queries_pool_total_balance() {
+        let mut contract = setup();
+        set_context("keeper.testnet", 0);
+        contract.measure_yield();
+        let calls = function_calls();
+        assert_eq!(
+            calls[0],
+            (
+                account("staking.testnet"),
+                "get_account_total_balance".to_string(),
+                0
+            )
+        );
+        assert_eq!(calls[1].1, "on_measure_yield");
+    }
+
+    #[test]
+    fn test_prize_is_growth_over_principal() {
+        let mut contract = setup();
+        set_context("alice.testnet", 100 * MIN_DEPOSIT);
+        contract.invest(U128(100 * MIN_DEPOSIT));
+        contract.sync_stake();
+
+        set_callback_context(pool_result(107 * MIN_DEPOSIT));
+        let measurement = contract
+            .on_measure_yield(vec![account("staking.testnet")])
+            .unwrap();
+
+        assert_eq!(measurement.principal, U128(100 * MIN_DEPOSIT));
+        assert_eq!(measurement.prize, U128(7 * MIN_DEPOSIT));
+        assert_eq!(contract.get_round_yield(0), Some(measurement));
+    }
+
+    #[test]
+    fn test_unstaking_principal_is_not_counted_as_prize() {
+        let mut contract = setup();
+        set_context("alice.testnet", 100 * MIN_DEPOSIT);
+        contract.invest(U128(100 * MIN_DEPOSIT));
+        contract.sync_stake();
+        set_context("alice.testnet", 0);
+        contract.withdraw(Ledger::Investor, U128(40 * MIN_DEPOSIT));
+
+        set_callback_context(pool_result(103 * MIN_DEPOSIT));
+        let measurement = contract
+            .on_measure_yield(vec![account("staking.testnet")])
+            .unwrap();
+
+        assert_eq!(measurement.principal, U128(100 * MIN_DEPOSIT));
+        assert_eq!(measurement.prize, U128(3 * MIN_DEPOSIT));
+    }
+
+    #[test]
+    fn test_pool_below_principal_yields_no_prize() {
+        let mut contract = setup();
+        set_context("alice.testnet", 100 * MIN_DEPOSIT);
+        contract.invest(U128(100 * MIN_DEPOSIT));
+        contract.sync_stake();
+
+        set_callback_context(pool_result(95 * MIN_DEPOSIT));
+        let measurement = contract
+            .on_measure_yield(vec![account("staking.testnet")])
+            .unwrap();
+        assert_eq!(measurement.prize, U128(0));
+    }
+
+    #[test]
+    fn test_failed_pool_query_records_nothing() {
+        let mut contract = setup();
+        set_callback_context(vec![PromiseResult::Failed]);
+        assert_eq!(
+            contract.on_measure_yield(vec![account("staking.testnet")]),
+            None
+        );
+        assert_eq!(contract.get_round_yield(0), None);
+    }
+
+    fn tiers(spec: &[(u32, u32)]) -> Vec<PrizeTier> {
+        spec.iter()
+            .map(|&(winners, share_bps)| PrizeTier { winners, share_bps })
+            .collect()
+    }
+
+    #[test]
+    fn test_prize_shares_follow_tiers() {
+        let shares = prize_shares(10_000, &tiers(&[(1, 5_000), (5, 800), (20, 50)]));
+        assert_eq!(shares.len(), 26);
+        assert_eq!(shares[0], 5_000);
+        assert_eq!(shares[1..6], [800; 5]);
+        assert_eq!(shares[6..], [50; 20]);
+    }
+
+    #[test]
+    fn test_prize_shares_give_dust_to_first_place() {
+        let shares = prize_shares(1_001, &tiers(&[(1, 5_000), (5, 800), (20, 50)]));
+        // 500 + 5 * 80 + 20 * 5 = 1000, leaving 1 of dust.
+        assert_eq!(shares[0], 501);
+        assert_eq!(shares.iter().sum::<u128>(), 1_001);
+    }
+
+    #[test]
+    fn test_owner_sets_prize_tiers() {
+        let mut contract = setup();
+        set_context("owner.testnet", 0);
+        let new_tiers = tiers(&[(1, 5_000), (5, 800), (20, 50)]);
+        contract.set_prize_tiers(new_tiers.clone());
+        assert_eq!(contract.get_prize_tiers(), new_tiers);
+    }
+
+    #[test]
+    #[should_panic(expected = "Prize tiers must add up to 10000 basis points")]
+    fn test_prize_tiers_must_cover_whole_prize() {
+        let mut contract = setup();
+        set_context("owner.testnet", 0);
+        contract.set_prize_tiers(tiers(&[(1, 5_000), (4, 1_000)]));
+    }
+
+    #[test]
+    #[should_panic(expected = "Prize tiers may pay at most 50 winners")]
+    fn test_prize_tiers_are_capped() {
+        let mut contract = setup();
+        set_context("owner.testnet", 0);
+        contract.set_prize_tiers(tiers(&[(100, 100)]));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_prize_tiers_are_owner_only() {
+        let mut contract = setup();
+        set_context("bob.testnet", 0);
+        contract.set_prize_tiers(PrizeTier::single());
+    }
+}
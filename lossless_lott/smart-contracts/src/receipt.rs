@@ -0,0 +1,365 @@
+//! Draw receipts. A settled draw keeps its seed, the total weight it was
+//! drawn from, the winning tickets and a hash chain over every
+//! `twab_update` event logged before it was committed, which stays the same
+//! size however many players there are. `replay` checks the exported events
+//! against that hash, rebuilds the weights in slot order from them and
+//! re-runs the draw with the same RNG.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{Base64VecU8, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId};
+use rand::Rng;
+
+use crate::selection::{rng_from_seed, TicketIndex};
+use crate::{Contract, ContractExt, Round};
+
+/// Everything needed to re-derive a round's winner.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DrawReceipt {
+    pub round_id: u64,
+    pub commit_block_height: U64,
+    pub reveal_block_height: U64,
+    /// Random seed of the reveal block.
+    pub random_seed: Base64VecU8,
+    /// Hash chain of the entropy contributed to the round.
+    pub entropy: Base64VecU8,
+    /// `Round::draw_seed(random_seed, entropy, round_id)`.
+    pub seed: Base64VecU8,
+    /// Length of the round in seconds, the weight of a ticket held for all
+    /// of it.
+    pub round_seconds: U64,
+    /// Hash chain over the first `twab_updates` `twab_update` events, taken
+    /// when the round was locked.
+    pub twab_hash: Base64VecU8,
+    pub twab_updates: U64,
+    /// Root of the weight tree when the winners were drawn.
+    pub total_weight: U128,
+    /// Winners in draw order; each was drawn from the weight left once the
+    /// ones before them were taken out.
+    pub winners: Vec<Winner>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Winner {
+    pub account_id: AccountId,
+    pub ticket: U128,
+    pub prize: U128,
+}
+
+/// A player's weight in a round.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Participant {
+    pub account_id: AccountId,
+    pub weight: U128,
+}
+
+/// Data of a `twab_update` event: a player's tickets and weight in
+/// `round_id` after their balance changed.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TwabUpdate {
+    pub account_id: AccountId,
+    pub slot: u64,
+    pub round_id: u64,
+    pub tickets: U128,
+    pub weight: U128,
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_draw_receipt(&self, round_id: u64) -> Option<DrawReceipt> {
+        self.draw_receipts.get(&round_id)
+    }
+
+    /// Players of the current round in slot order with their weights, which
+    /// stop changing once the round has ended.
+    pub fn get_participants(&self, from_index: u64, limit: u64) -> Vec<Participant> {
+        let round = self.current_round();
+        let end = std::cmp::min(from_index.saturating_add(limit), self.slot_owners.len());
+        (from_index..end)
+            .map(|index| self.participant(&round, index))
+            .collect()
+    }
+}
+
+impl Contract {
+    fn participant(&self, round: &Round, index: u64) -> Participant {
+        let account_id = self.slot_owners.get(index).expect("Slot has no owner");
+        let weight = self.twab_weight(&account_id, round);
+        Participant {
+            account_id,
+            weight: U128(weight),
+        }
+    }
+}
+
+/// Extends the `twab_update` hash chain by one event.
+pub fn hash_twab_update(hash: &[u8], update: &TwabUpdate) -> Vec<u8> {
+    let mut preimage = hash.to_vec();
+    preimage.extend_from_slice(&(update.account_id.as_bytes().len() as u32).to_le_bytes());
+    preimage.extend_from_slice(update.account_id.as_bytes());
+    preimage.extend_from_slice(&update.slot.to_le_bytes());
+    preimage.extend_from_slice(&update.round_id.to_le_bytes());
+    preimage.extend_from_slice(&update.tickets.0.to_le_bytes());
+    preimage.extend_from_slice(&update.weight.0.to_le_bytes());
+    env::sha256(&preimage)
+}
+
+/// Rebuilds a round's participants in slot order from the contract's
+/// `twab_update` events, given in the order they were logged, after checking
+/// the ones logged before the draw against the receipt. Players whose last
+/// update was in an earlier round held their tickets for all of it.
+pub fn participants(
+    receipt: &DrawReceipt,
+    updates: &[TwabUpdate],
+) -> Result<Vec<Participant>, String> {
+    let count = receipt.twab_updates.0 as usize;
+    if updates.len() < count {
+        return Err(format!(
+            "Expected {} twab_update events, got {}",
+            count,
+            updates.len()
+        ));
+    }
+    let updates = &updates[..count];
+    let hash = updates
+        .iter()
+        .fold(vec![], |hash, update| hash_twab_update(&hash, update));
+    if hash != receipt.twab_hash.0 {
+        return Err("twab_update events do not match the receipt hash".to_string());
+    }
+
+    let mut slots: Vec<Option<&TwabUpdate>> = vec![];
+    for update in updates {
+        let slot = update.slot as usize;
+        if slots.len() <= slot {
+            slots.resize(slot + 1, None);
+        }
+        slots[slot] = Some(update);
+    }
+    Ok(slots
+        .into_iter()
+        .flatten()
+        .map(|update| Participant {
+            account_id: update.account_id.clone(),
+            weight: if update.round_id == receipt.round_id {
+                update.weight
+            } else {
+                U128(update.tickets.0 * receipt.round_seconds.0 as u128)
+            },
+        })
+        .collect())
+}
+
+/// Re-runs a draw from its receipt and the contract's `twab_update` events,
+/// checking each step against what the contract recorded.
+pub fn replay(receipt: &DrawReceipt, updates: &[TwabUpdate]) -> Result<Vec<AccountId>, String> {
+    let participants = participants(receipt, updates)?;
+    let seed = Round::draw_seed(&receipt.random_seed.0, &receipt.entropy.0, receipt.round_id);
+    if seed != receipt.seed.0 {
+        return Err("Seed does not match the random seed and entropy".to_string());
+    }
+    let total: u128 = participants
+        .iter()
+        .map(|participant| participant.weight.0)
+        .sum();
+    if total != receipt.total_weight.0 {
+        return Err(format!(
+            "Expected a total weight of {}, got {}",
+            receipt.total_weight.0, total
+        ));
+    }
+
+    let mut rng = rng_from_seed(&seed);
+    let mut winners: Vec<AccountId> = vec![];
+    for expected in &receipt.winners {
+        let index = TicketIndex::new(
+            participants
+                .iter()
+                .filter(|participant| !winners.contains(&participant.account_id))
+                .map(|participant| (participant.account_id.clone(), participant.weight.0)),
+        );
+        if index.total() == 0 {
+            return Err(format!("No tickets left to draw {}", expected.account_id));
+        }
+        let ticket = rng.gen_range(0..index.total());
+        if ticket != expected.ticket.0 {
+            return Err(format!(
+                "Expected ticket {}, drew {}",
+                expected.ticket.0, ticket
+            ));
+        }
+        let winner = index.owner_of(ticket);
+        if *winner != expected.account_id {
+            return Err(format!(
+                "Expected {} to win, got {}",
+                expected.account_id, winner
+            ));
+        }
+        winners.push(winner.clone());
+    }
+    Ok(winners)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::round::DEFAULT_ROUND_DURATION;
+    use crate::test_utils::*;
+    use crate::PrizeTier;
+    use near_sdk::serde_json::{self, Value};
+    use near_sdk::test_utils::get_logs;
+
+    /// Data of the `twab_update` events logged by the last call.
+    fn twab_updates() -> Vec<TwabUpdate> {
+        get_logs()
+            .iter()
+            .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+            .map(|event| serde_json::from_str::<Value>(event).unwrap())
+            .filter(|event| event["event"] == "twab_update")
+            .map(|event| serde_json::from_value(event["data"][0].clone()).unwrap())
+            .collect()
+    }
+
+    /// Plays a round with three players and exports its participants just
+    /// before the winner is revealed, along with every `twab_update` event.
+    fn settled_round() -> (Contract, Vec<Participant>, Vec<TwabUpdate>) {
+        settled_round_with(PrizeTier::single())
+    }
+
+    fn settled_round_with(tiers: Vec<PrizeTier>) -> (Contract, Vec<Participant>, Vec<TwabUpdate>) {
+        let mut contract = setup();
+        contract.prize_tiers = tiers;
+        let mut updates = vec![];
+        for (name, tickets, timestamp) in [
+            ("a.testnet", 5, 0),
+            ("b.testnet", 1, DEFAULT_ROUND_DURATION / 2),
+            ("c.testnet", 9, DEFAULT_ROUND_DURATION / 4),
+        ] {
+            set_context_at(name, tickets * MIN_DEPOSIT, timestamp, 0);
+            contract.play(U128(tickets * MIN_DEPOSIT));
+            updates.extend(twab_updates());
+        }
+        set_context_at("keeper.testnet", 0, DEFAULT_ROUND_DURATION, 0);
+        contract.draw();
+        let participants = contract.get_participants(0, 10);
+        settle(&mut contract, 18 * MIN_DEPOSIT, DEFAULT_ROUND_DURATION);
+        updates.extend(twab_updates());
+        (contract, participants, updates)
+    }
+
+    fn winner_ids(receipt: &DrawReceipt) -> Vec<AccountId> {
+        receipt
+            .winners
+            .iter()
+            .map(|w| w.account_id.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_draw_persists_receipt() {
+        let (contract, participants, _) = settled_round();
+        let receipt = contract.get_draw_receipt(0).unwrap();
+        let total: u128 = participants.iter().map(|p| p.weight.0).sum();
+        assert_eq!(receipt.total_weight, U128(total));
+        assert_eq!(receipt.twab_updates, U64(3));
+        assert_eq!(
+            receipt.round_seconds,
+            U64(DEFAULT_ROUND_DURATION / 1_000_000_000)
+        );
+        assert_eq!(receipt.winners, contract.get_round(0).unwrap().winners);
+    }
+
+    #[test]
+    fn test_participants_rebuilt_from_events() {
+        let (contract, participants, updates) = settled_round();
+        let receipt = contract.get_draw_receipt(0).unwrap();
+        assert_eq!(super::participants(&receipt, &updates), Ok(participants));
+    }
+
+    #[test]
+    fn test_replay_reproduces_winner() {
+        let (contract, _, updates) = settled_round();
+        let receipt = contract.get_draw_receipt(0).unwrap();
+        assert_eq!(replay(&receipt, &updates), Ok(winner_ids(&receipt)));
+    }
+
+    #[test]
+    fn test_replay_reproduces_every_tier() {
+        let tiers = vec![
+            PrizeTier {
+                winners: 1,
+                share_bps: 5_000,
+            },
+            PrizeTier {
+                winners: 2,
+                share_bps: 2_500,
+            },
+        ];
+        let (contract, _, updates) = settled_round_with(tiers);
+        let receipt = contract.get_draw_receipt(0).unwrap();
+        assert_eq!(receipt.winners.len(), 3);
+        assert_eq!(replay(&receipt, &updates), Ok(winner_ids(&receipt)));
+    }
+
+    #[test]
+    fn test_replay_rejects_altered_weights() {
+        let (contract, _, mut updates) = settled_round();
+        let receipt = contract.get_draw_receipt(0).unwrap();
+        updates[1].weight.0 += 1;
+        assert_eq!(
+            replay(&receipt, &updates),
+            Err("twab_update events do not match the receipt hash".to_string())
+        );
+    }
+
+    #[test]
+    fn test_replay_rejects_missing_events() {
+        let (contract, _, mut updates) = settled_round();
+        let receipt = contract.get_draw_receipt(0).unwrap();
+        updates.remove(0);
+        assert_eq!(
+            replay(&receipt, &updates[..1]),
+            Err("Expected 3 twab_update events, got 1".to_string())
+        );
+        assert!(replay(&receipt, &updates).is_err());
+    }
+
+    #[test]
+    fn test_replay_rejects_altered_seed() {
+        let (contract, _, updates) = settled_round();
+        let mut receipt = contract.get_draw_receipt(0).unwrap();
+        receipt.random_seed.0[0] ^= 1;
+        assert!(replay(&receipt, &updates).is_err());
+    }
+
+    #[test]
+    fn test_players_untouched_in_a_round_are_rebuilt_from_earlier_events() {
+        let (mut contract, _, mut updates) = settled_round();
+        set_context_at(
+            "d.testnet",
+            2 * MIN_DEPOSIT,
+            DEFAULT_ROUND_DURATION * 3 / 2,
+            2,
+        );
+        contract.play(U128(2 * MIN_DEPOSIT));
+        updates.extend(twab_updates());
+
+        set_context_at("keeper.testnet", 0, 2 * DEFAULT_ROUND_DURATION, 3);
+        contract.draw();
+        let participants = contract.get_participants(0, 10);
+        set_callback_context(pool_result(20 * MIN_DEPOSIT));
+        contract.on_draw_yield(vec![account("staking.testnet")]);
+        set_block_context("keeper.testnet", 2 * DEFAULT_ROUND_DURATION, 4, [9; 32]);
+        contract.draw();
+        updates.extend(twab_updates());
+
+        let receipt = contract.get_draw_receipt(1).unwrap();
+        assert!(!receipt.winners.is_empty());
+        assert_eq!(super::participants(&receipt, &updates), Ok(participants));
+        assert_eq!(replay(&receipt, &updates), Ok(winner_ids(&receipt)));
+    }
+}
@@ -0,0 +1,607 @@
+//! The round lifecycle: deposits accrue odds while a round is open, and once
+//! its end time has passed anyone can call `draw` to lock it and, from a
+//! later block, again to reveal the winner.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{Base64VecU8, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
+use near_sdk::{env, near_bindgen, AccountId, Balance, Gas, Promise, PromiseOrValue};
+
+use crate::events;
+use crate::prize::prize_shares;
+use crate::receipt::{DrawReceipt, Winner};
+use crate::staking::{assert_enough_gas, GAS_FOR_VIEW};
+use crate::twab::round_seconds;
+use crate::{Contract, ContractExt};
+
+/// One week, in nanoseconds.
+pub const DEFAULT_ROUND_DURATION: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+const GAS_FOR_ON_DRAW_YIELD: Gas = Gas(10_000_000_000_000);
+
+/// Upper bound on a single `contribute_entropy` call.
+const MAX_ENTROPY_LEN: usize = 64;
+
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum RoundStatus {
+    /// Accepting deposits; cannot be drawn before `end_timestamp`.
+    Open,
+    /// A draw has started and is waiting on the staking pool for the yield.
+    Locked,
+    /// The prize is known and the winner waits to be revealed from a later
+    /// block's random seed.
+    Drawing,
+    /// The winner has been credited and the next round is open.
+    Settled,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Round {
+    pub id: u64,
+    pub status: RoundStatus,
+    pub start_timestamp: U64,
+    pub end_timestamp: U64,
+    /// Prize left after the yield split, including `jackpot`.
+    pub prize: U128,
+    /// Part of the prize rolled over from earlier rounds.
+    pub jackpot: U128,
+    pub winners: Vec<Winner>,
+    /// Block the round was locked at. The winner is drawn from the seed of a
+    /// strictly later block.
+    pub commit_block_height: Option<U64>,
+    /// Hash chain of the entropy players contributed while the round was
+    /// open, frozen once it is locked.
+    pub entropy: Base64VecU8,
+    /// `twab_hash` and `twab_updates` when the round was locked.
+    pub twab_hash: Base64VecU8,
+    pub twab_updates: u64,
+}
+
+impl Round {
+    pub(crate) fn open(id: u64, start_timestamp: u64, duration: u64) -> Self {
+        Self {
+            id,
+            status: RoundStatus::Open,
+            start_timestamp: U64(start_timestamp),
+            end_timestamp: U64(start_timestamp + duration),
+            prize: U128(0),
+            jackpot: U128(0),
+            winners: vec![],
+            commit_block_height: None,
+            entropy: Base64VecU8(Vec::new()),
+            twab_hash: Base64VecU8(Vec::new()),
+            twab_updates: 0,
+        }
+    }
+
+    /// `sha256(random_seed || entropy || round_id)`: mixes a round's entropy,
+    /// the reveal block's random seed and the round id into the seed the
+    /// winner is drawn with.
+    pub fn draw_seed(random_seed: &[u8], entropy: &[u8], round_id: u64) -> Vec<u8> {
+        let mut preimage = random_seed.to_vec();
+        preimage.extend_from_slice(entropy);
+        preimage.extend_from_slice(&round_id.to_le_bytes());
+        env::sha256(&preimage)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Settles the current round in two steps. The first call, once the
+    /// round is over, commits to the current block and measures the yield the
+    /// staking pool paid. A second call from a later block reveals the winner
+    /// from that block's random seed, credits the prize and opens the next
+    /// round.
+    pub fn draw(&mut self) -> PromiseOrValue<Round> {
+        let round = self.current_round();
+        match round.status {
+            RoundStatus::Open => PromiseOrValue::Promise(self.commit_draw(round)),
+            RoundStatus::Drawing => PromiseOrValue::Value(self.reveal_draw(round)),
+            _ => panic!("Draw already in progress"),
+        }
+    }
+
+    /// Mixes caller-supplied bytes into the current round's seed. Anyone may
+    /// contribute while the round is open. Entropy is frozen when the draw
+    /// is committed: the producer of the reveal block knows its random seed
+    /// in advance and could otherwise grind entropy to pick the winner.
+    pub fn contribute_entropy(&mut self, entropy: Base64VecU8) {
+        let mut round = self.current_round();
+        assert_eq!(
+            round.status,
+            RoundStatus::Open,
+            "Entropy can only be contributed while round {} is open",
+            round.id
+        );
+        assert!(
+            entropy.0.len() <= MAX_ENTROPY_LEN,
+            "Entropy must be at most {} bytes",
+            MAX_ENTROPY_LEN
+        );
+        let mut preimage = round.entropy.0;
+        preimage.extend_from_slice(env::predecessor_account_id().as_bytes());
+        preimage.extend_from_slice(&entropy.0);
+        round.entropy = Base64VecU8(env::sha256(&preimage));
+        self.rounds.insert(&round.id, &round);
+    }
+
+    /// Records the prize once the pool balances are known. If a pool could
+    /// not be read the round re-opens so the draw can be retried.
+    #[private]
+    pub fn on_draw_yield(&mut self, pool_ids: Vec<AccountId>) -> Round {
+        let mut round = self.current_round();
+        assert_eq!(round.status, RoundStatus::Locked, "Round is not locked");
+
+        let total_balance = match self.record_pool_totals(&pool_ids) {
+            Some(total_balance) => total_balance,
+            None => {
+                env::log_str(&format!("Draw for round {} aborted", round.id));
+                round.status = RoundStatus::Open;
+                round.commit_block_height = None;
+                self.rounds.insert(&round.id, &round);
+                return round;
+            }
+        };
+
+        round.status = RoundStatus::Drawing;
+        let measured = self.record_yield(total_balance).prize.0;
+        round.jackpot = U128(std::cmp::min(self.jackpot, measured));
+        round.prize = U128(self.split_yield(round.id, measured));
+        self.rounds.insert(&round.id, &round);
+        round
+    }
+
+    pub fn get_round(&self, round_id: u64) -> Option<Round> {
+        self.rounds.get(&round_id)
+    }
+
+    pub fn get_current_round(&self) -> Round {
+        self.current_round()
+    }
+
+    /// Changes the length of rounds opened from now on.
+    pub fn set_round_duration(&mut self, round_duration: U64) {
+        self.assert_owner();
+        assert!(round_duration.0 > 0, "Round duration must be positive");
+        self.round_duration = round_duration.0;
+    }
+}
+
+impl Contract {
+    pub(crate) fn current_round(&self) -> Round {
+        self.rounds
+            .get(&self.round_id)
+            .expect("Current round is missing")
+    }
+
+    /// Locks the round at the current block and asks the pools for their
+    /// balances.
+    fn commit_draw(&mut self, mut round: Round) -> Promise {
+        assert!(
+            env::block_timestamp() >= round.end_timestamp.0,
+            "Round {} cannot be drawn before {}",
+            round.id,
+            round.end_timestamp.0
+        );
+        assert_enough_gas(Gas(
+            self.staking_pools.len() as u64 * GAS_FOR_VIEW.0 + GAS_FOR_ON_DRAW_YIELD.0
+        ));
+        // Expired prizes leave the principal before the pool is measured,
+        // so they come back as part of this round's prize.
+        self.sweep_expired_prizes();
+
+        round.status = RoundStatus::Locked;
+        round.commit_block_height = Some(U64(env::block_height()));
+        // Weights in the round stop changing once it has ended, so the
+        // events logged so far fix every participant's weight.
+        round.twab_hash = Base64VecU8(self.twab_hash.clone());
+        round.twab_updates = self.twab_updates;
+        self.rounds.insert(&round.id, &round);
+        env::log_str(&format!(
+            "Round {} committed at block {} with entropy {}",
+            round.id,
+            env::block_height(),
+            near_sdk::base64::encode(&round.entropy.0)
+        ));
+
+        self.query_pool_totals().then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_ON_DRAW_YIELD)
+                .on_draw_yield(self.staking_pool_ids()),
+        )
+    }
+
+    /// Draws the winner from this block's random seed, which did not exist
+    /// when the round was committed, and settles the round.
+    fn reveal_draw(&mut self, mut round: Round) -> Round {
+        let commit_block_height = round
+            .commit_block_height
+            .expect("Round was not committed")
+            .0;
+        assert!(
+            env::block_height() > commit_block_height,
+            "Round {} cannot be revealed before block {}",
+            round.id,
+            commit_block_height + 1
+        );
+
+        let random_seed = env::random_seed();
+        let seed = Round::draw_seed(&random_seed, &round.entropy.0, round.id);
+        let shares = prize_shares(round.prize.0, &self.prize_tiers);
+        let total_weight = self.total_twab_weight();
+        let picks = if total_weight > 0 {
+            self.select_winners(&seed, shares.len())
+        } else {
+            vec![]
+        };
+        let winners: Vec<Winner> = picks
+            .into_iter()
+            .zip(shares.iter())
+            .map(|((ticket, account_id), &prize)| Winner {
+                account_id,
+                ticket: U128(ticket),
+                prize: U128(prize),
+            })
+            .collect();
+        let receipt = DrawReceipt {
+            round_id: round.id,
+            commit_block_height: U64(commit_block_height),
+            reveal_block_height: U64(env::block_height()),
+            random_seed: Base64VecU8(random_seed),
+            entropy: round.entropy.clone(),
+            seed: Base64VecU8(seed),
+            round_seconds: U64(round_seconds(&round)),
+            twab_hash: round.twab_hash.clone(),
+            twab_updates: U64(round.twab_updates),
+            total_weight: U128(total_weight),
+            winners: winners.clone(),
+        };
+        self.draw_receipts.insert(&round.id, &receipt);
+
+        // Prizes stay staked, either as claimable prizes or compounded.
+        for winner in &winners {
+            self.pay_prize(&winner.account_id, round.id, winner.prize.0);
+            env::log_str(&format!(
+                "Round {} won by {} for {} with ticket {}",
+                round.id, winner.account_id, winner.prize.0, winner.ticket.0
+            ));
+        }
+        self.settle_jackpot(&round, &winners);
+        round.winners = winners;
+        round.status = RoundStatus::Settled;
+        self.rounds.insert(&round.id, &round);
+
+        self.round_id += 1;
+        let next = Round::open(self.round_id, env::block_timestamp(), self.round_duration);
+        self.rounds.insert(&next.id, &next);
+        round
+    }
+
+    /// Whatever the winners did not take, whether the round had no eligible
+    /// players or places went unfilled, rolls over into the jackpot.
+    fn settle_jackpot(&mut self, round: &Round, winners: &[Winner]) {
+        let paid: Balance = winners.iter().map(|winner| winner.prize.0).sum();
+        if round.jackpot.0 > 0 && paid > 0 {
+            events::emit(
+                "jackpot_awarded",
+                json!({
+                    "round_id": round.id,
+                    "amount": U128(std::cmp::min(round.jackpot.0, paid)),
+                }),
+            );
+        }
+        self.jackpot = round.prize.0 - paid;
+        if self.jackpot > 0 {
+            events::emit(
+                "jackpot_rollover",
+                json!({
+                    "round_id": round.id,
+                    "amount": U128(self.jackpot),
+                }),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::PrizeTier;
+    use near_sdk::test_utils::get_logs;
+    use near_sdk::PromiseResult;
+
+    /// An invested pool plus a single player, with the first round over.
+    fn setup_round_over() -> Contract {
+        let mut contract = setup();
+        set_context("alice.testnet", 100 * MIN_DEPOSIT);
+        contract.invest(U128(100 * MIN_DEPOSIT));
+        set_context("bob.testnet", 10 * MIN_DEPOSIT);
+        contract.play(U128(10 * MIN_DEPOSIT));
+        contract.sync_stake();
+        set_context_at("keeper.testnet", 0, DEFAULT_ROUND_DURATION, 7);
+        contract
+    }
+
+    #[test]
+    fn test_first_round_opens_on_init() {
+        let contract = setup();
+        let round = contract.get_current_round();
+        assert_eq!(round.id, 0);
+        assert_eq!(round.status, RoundStatus::Open);
+        assert_eq!(round.end_timestamp, U64(DEFAULT_ROUND_DURATION));
+    }
+
+    #[test]
+    #[should_panic(expected = "Round 0 cannot be drawn before")]
+    fn test_draw_rejected_before_round_end() {
+        let mut contract = setup();
+        set_context("bob.testnet", MIN_DEPOSIT);
+        contract.play(U128(MIN_DEPOSIT));
+        set_context_at("keeper.testnet", 0, DEFAULT_ROUND_DURATION - 1, 0);
+        contract.draw();
+    }
+
+    #[test]
+    fn test_draw_locks_round_and_queries_pool() {
+        let mut contract = setup_round_over();
+        contract.draw();
+        assert_eq!(contract.get_current_round().status, RoundStatus::Locked);
+        let calls = function_calls();
+        assert_eq!(calls[0].1, "get_account_total_balance");
+        assert_eq!(calls[1].1, "on_draw_yield");
+    }
+
+    #[test]
+    #[should_panic(expected = "Draw already in progress")]
+    fn test_draw_rejected_while_locked() {
+        let mut contract = setup_round_over();
+        contract.draw();
+        contract.draw();
+    }
+
+    #[test]
+    fn test_draw_commits_to_lock_block() {
+        let mut contract = setup_round_over();
+        set_block_context("keeper.testnet", DEFAULT_ROUND_DURATION, 42, [0; 32]);
+        contract.draw();
+        let round = contract.get_current_round();
+        assert_eq!(round.commit_block_height, Some(U64(42)));
+        assert_eq!(contract.get_draw_receipt(0), None);
+    }
+
+    #[test]
+    fn test_yield_waits_for_reveal() {
+        let mut contract = setup_round_over();
+        contract.draw();
+        set_callback_context(pool_result(105 * MIN_DEPOSIT));
+        let round = contract.on_draw_yield(vec![account("staking.testnet")]);
+        assert_eq!(round.status, RoundStatus::Drawing);
+        assert_eq!(round.prize, U128(5 * MIN_DEPOSIT));
+        assert!(round.winners.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Round 0 cannot be revealed before block 1")]
+    fn test_reveal_needs_later_block() {
+        let mut contract = setup_round_over();
+        contract.draw();
+        set_callback_context(pool_result(105 * MIN_DEPOSIT));
+        contract.on_draw_yield(vec![account("staking.testnet")]);
+        set_context_at("keeper.testnet", 0, DEFAULT_ROUND_DURATION, 7);
+        contract.draw();
+    }
+
+    #[test]
+    fn test_reveal_seeds_draw_from_later_block() {
+        let mut contract = setup_round_over();
+        contract.contribute_entropy(Base64VecU8(b"keeper".to_vec()));
+        contract.draw();
+        let settled = settle(&mut contract, 105 * MIN_DEPOSIT, DEFAULT_ROUND_DURATION);
+
+        let receipt = contract.get_draw_receipt(0).unwrap();
+        assert_eq!(receipt.commit_block_height, U64(0));
+        assert_eq!(receipt.reveal_block_height, U64(1));
+        assert_eq!(receipt.random_seed, Base64VecU8(vec![7; 32]));
+        assert_eq!(receipt.entropy, settled.entropy);
+        assert_eq!(
+            receipt.seed,
+            Base64VecU8(Round::draw_seed(&[7; 32], &settled.entropy.0, 0))
+        );
+    }
+
+    #[test]
+    fn test_entropy_changes_seed() {
+        let mut contract = setup_round_over();
+        let before = contract.get_current_round().entropy;
+        contract.contribute_entropy(Base64VecU8(vec![1, 2, 3]));
+        let after = contract.get_current_round().entropy;
+        assert_ne!(before, after);
+        assert_ne!(
+            Round::draw_seed(&[7; 32], &before.0, 0),
+            Round::draw_seed(&[7; 32], &after.0, 0)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Entropy can only be contributed while round 0 is open")]
+    fn test_entropy_is_frozen_once_committed() {
+        let mut contract = setup_round_over();
+        contract.draw();
+        set_callback_context(pool_result(105 * MIN_DEPOSIT));
+        contract.on_draw_yield(vec![account("staking.testnet")]);
+        set_context_at("keeper.testnet", 0, DEFAULT_ROUND_DURATION, 1);
+        contract.contribute_entropy(Base64VecU8(b"grind".to_vec()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Entropy must be at most 64 bytes")]
+    fn test_entropy_is_bounded() {
+        let mut contract = setup_round_over();
+        contract.contribute_entropy(Base64VecU8(vec![0; 65]));
+    }
+
+    #[test]
+    fn test_draw_settles_round_and_opens_next() {
+        let mut contract = setup_round_over();
+        contract.draw();
+        let settled = settle(&mut contract, 105 * MIN_DEPOSIT, DEFAULT_ROUND_DURATION);
+
+        assert_eq!(settled.status, RoundStatus::Settled);
+        assert_eq!(settled.prize, U128(5 * MIN_DEPOSIT));
+        assert_eq!(settled.winners.len(), 1);
+        assert_eq!(settled.winners[0].account_id, account("bob.testnet"));
+        assert_eq!(settled.winners[0].prize, U128(5 * MIN_DEPOSIT));
+        assert_eq!(contract.get_round(0), Some(settled));
+        assert_eq!(
+            contract.get_prizes(account("bob.testnet"))[0].amount,
+            U128(5 * MIN_DEPOSIT)
+        );
+        assert_eq!(contract.principal_in_pool(), U128(105 * MIN_DEPOSIT));
+
+        let next = contract.get_current_round();
+        assert_eq!(next.id, 1);
+        assert_eq!(next.status, RoundStatus::Open);
+        assert_eq!(
+            contract.get_round_yield(0).unwrap().prize,
+            U128(5 * MIN_DEPOSIT)
+        );
+    }
+
+    #[test]
+    fn test_tiers_pick_distinct_winners() {
+        let mut contract = setup_round_over();
+        set_context("owner.testnet", 0);
+        contract.set_prize_tiers(vec![
+            PrizeTier {
+                winners: 1,
+                share_bps: 6_000,
+            },
+            PrizeTier {
+                winners: 2,
+                share_bps: 2_000,
+            },
+        ]);
+        for name in ["carol.testnet", "dave.testnet"] {
+            set_context(name, MIN_DEPOSIT);
+            contract.play(U128(MIN_DEPOSIT));
+        }
+        set_context_at("keeper.testnet", 0, DEFAULT_ROUND_DURATION, 7);
+        contract.draw();
+        let total = contract.principal_in_pool().0 + 10 * MIN_DEPOSIT;
+        let settled = settle(&mut contract, total, DEFAULT_ROUND_DURATION);
+
+        let prizes: Vec<u128> = settled.winners.iter().map(|w| w.prize.0).collect();
+        assert_eq!(
+            prizes,
+            vec![6 * MIN_DEPOSIT, 2 * MIN_DEPOSIT, 2 * MIN_DEPOSIT]
+        );
+        let mut accounts: Vec<_> = settled.winners.iter().map(|w| &w.account_id).collect();
+        accounts.sort();
+        accounts.dedup();
+        assert_eq!(accounts.len(), 3);
+        assert_eq!(contract.total_prizes, 10 * MIN_DEPOSIT);
+    }
+
+    #[test]
+    fn test_unfilled_places_roll_over() {
+        let mut contract = setup_round_over();
+        set_context("owner.testnet", 0);
+        contract.set_prize_tiers(vec![PrizeTier {
+            winners: 4,
+            share_bps: 2_500,
+        }]);
+        set_context_at("keeper.testnet", 0, DEFAULT_ROUND_DURATION, 7);
+        contract.draw();
+        let settled = settle(&mut contract, 108 * MIN_DEPOSIT, DEFAULT_ROUND_DURATION);
+        assert_eq!(settled.winners.len(), 1);
+        assert_eq!(settled.winners[0].prize, U128(2 * MIN_DEPOSIT));
+
+        // The three empty places are still in the pool for the next round.
+        assert_eq!(contract.get_jackpot(), U128(6 * MIN_DEPOSIT));
+        set_context_at("keeper.testnet", 0, 2 * DEFAULT_ROUND_DURATION, 14);
+        contract.draw();
+        let next = settle(&mut contract, 108 * MIN_DEPOSIT, 2 * DEFAULT_ROUND_DURATION);
+        assert_eq!(next.prize, U128(6 * MIN_DEPOSIT));
+        assert_eq!(next.jackpot, U128(6 * MIN_DEPOSIT));
+    }
+
+    #[test]
+    fn test_next_round_only_counts_new_yield() {
+        let mut contract = setup_round_over();
+        contract.draw();
+        settle(&mut contract, 105 * MIN_DEPOSIT, DEFAULT_ROUND_DURATION);
+
+        set_context_at("keeper.testnet", 0, 2 * DEFAULT_ROUND_DURATION, 14);
+        contract.draw();
+        let settled = settle(&mut contract, 108 * MIN_DEPOSIT, 2 * DEFAULT_ROUND_DURATION);
+        assert_eq!(settled.id, 1);
+        assert_eq!(settled.prize, U128(3 * MIN_DEPOSIT));
+    }
+
+    #[test]
+    fn test_failed_pool_query_reopens_round() {
+        let mut contract = setup_round_over();
+        contract.draw();
+
+        set_callback_context(vec![PromiseResult::Failed]);
+        let round = contract.on_draw_yield(vec![account("staking.testnet")]);
+        assert_eq!(round.status, RoundStatus::Open);
+        assert_eq!(round.commit_block_height, None);
+        assert_eq!(contract.get_current_round().id, 0);
+        assert_eq!(
+            contract.players.get(&account("bob.testnet")),
+            Some(10 * MIN_DEPOSIT)
+        );
+    }
+
+    #[test]
+    fn test_empty_round_rolls_into_jackpot() {
+        let mut contract = setup();
+        set_context("alice.testnet", 100 * MIN_DEPOSIT);
+        contract.invest(U128(100 * MIN_DEPOSIT));
+        contract.sync_stake();
+        set_context_at("keeper.testnet", 0, DEFAULT_ROUND_DURATION, 7);
+        contract.draw();
+        let empty = settle(&mut contract, 104 * MIN_DEPOSIT, DEFAULT_ROUND_DURATION);
+
+        assert_eq!(empty.status, RoundStatus::Settled);
+        assert!(empty.winners.is_empty());
+        assert_eq!(contract.get_jackpot(), U128(4 * MIN_DEPOSIT));
+        assert_eq!(contract.total_stake, 100 * MIN_DEPOSIT);
+        assert!(get_logs()[0].starts_with("EVENT_JSON:"));
+        assert!(get_logs()[0].contains("\"event\":\"jackpot_rollover\""));
+
+        set_context_at("bob.testnet", MIN_DEPOSIT, DEFAULT_ROUND_DURATION, 7);
+        contract.play(U128(MIN_DEPOSIT));
+        set_context_at("keeper.testnet", 0, 2 * DEFAULT_ROUND_DURATION, 14);
+        contract.draw();
+        let won = settle(&mut contract, 107 * MIN_DEPOSIT, 2 * DEFAULT_ROUND_DURATION);
+
+        assert_eq!(won.prize, U128(7 * MIN_DEPOSIT));
+        assert_eq!(won.jackpot, U128(4 * MIN_DEPOSIT));
+        assert_eq!(won.winners[0].prize, U128(7 * MIN_DEPOSIT));
+        assert_eq!(contract.get_jackpot(), U128(0));
+        assert!(get_logs().iter().any(|log| log.contains("jackpot_awarded")));
+    }
+
+    #[test]
+    fn test_owner_sets_round_duration() {
+        let mut contract = setup();
+        set_context("owner.testnet", 0);
+        contract.set_round_duration(U64(60));
+        assert_eq!(contract.round_duration, 60);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_round_duration_is_owner_only() {
+        let mut contract = setup();
+        set_context("bob.testnet", 0);
+        contract.set_round_duration(U64(60));
+    }
+}
@@ -0,0 +1,221 @@
+//! Weighted winner selection. Each player holds one ticket per full
+//! `min_deposit` in their balance, so splitting a deposit across accounts
+//! never improves the odds, and tickets count for as long as they were held
+//! during the round (see `twab`).
+use near_sdk::{AccountId, Balance};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::twab::round_seconds;
+use crate::Contract;
+
+/// Players and their cumulative ticket counts, searchable in O(log n) once
+/// built. The contract keeps the same weights in a `FenwickTree`; this
+/// in-memory form is for checking draws off-chain.
+pub struct TicketIndex {
+    accounts: Vec<AccountId>,
+    cumulative: Vec<u128>,
+}
+
+impl TicketIndex {
+    /// Builds the index, leaving out accounts with no tickets.
+    pub fn new(weights: impl IntoIterator<Item = (AccountId, u128)>) -> Self {
+        let mut accounts = vec![];
+        let mut cumulative = vec![];
+        let mut total = 0u128;
+        for (account_id, tickets) in weights {
+            if tickets == 0 {
+                continue;
+            }
+            total += tickets;
+            accounts.push(account_id);
+            cumulative.push(total);
+        }
+        Self {
+            accounts,
+            cumulative,
+        }
+    }
+
+    pub fn total(&self) -> u128 {
+        self.cumulative.last().copied().unwrap_or(0)
+    }
+
+    /// Returns the owner of the ticket with the given zero-based number.
+    pub fn owner_of(&self, ticket: u128) -> &AccountId {
+        assert!(ticket < self.total(), "Ticket out of range");
+        let position = self.cumulative.partition_point(|&end| end <= ticket);
+        &self.accounts[position]
+    }
+
+    /// Picks a ticket uniformly at random and returns its owner.
+    pub fn pick(&self, rng: &mut impl Rng) -> &AccountId {
+        self.owner_of(rng.gen_range(0..self.total()))
+    }
+}
+
+/// Number of tickets a balance is worth; remainders below one ticket do not
+/// count.
+pub fn tickets_for(balance: Balance, min_deposit: Balance) -> u128 {
+    balance / min_deposit
+}
+
+/// Expands a block random seed into an RNG.
+pub fn rng_from_seed(random_seed: &[u8]) -> StdRng {
+    let mut seed = [0u8; 32];
+    let bytes_to_copy = std::cmp::min(random_seed.len(), 32);
+    seed[..bytes_to_copy].copy_from_slice(&random_seed[..bytes_to_copy]);
+    StdRng::from_seed(seed)
+}
+
+impl Contract {
+    /// Draws up to `count` distinct players, each the owner of a uniformly
+    /// random ticket-second among the players not drawn yet, and returns the
+    /// winning tickets along with their owners in draw order.
+    pub(crate) fn select_winners(&self, seed: &[u8], count: usize) -> Vec<(u128, AccountId)> {
+        let round = self.current_round();
+        let seconds = round_seconds(&round);
+        let mut remaining = self.twab_tree.total(round.id, seconds);
+        assert!(remaining > 0, "No players to select a winner from");
+        let mut rng = rng_from_seed(seed);
+        let mut excluded = vec![];
+        let mut winners = vec![];
+        while winners.len() < count && remaining > 0 {
+            let ticket = rng.gen_range(0..remaining);
+            let slot = self
+                .twab_tree
+                .find_excluding(round.id, seconds, ticket, &excluded);
+            let winner = self.slot_owners.get(slot - 1).expect("Slot has no owner");
+            let weight = self.twab_weight(&winner, &round);
+            excluded.push((slot, weight));
+            remaining -= weight;
+            winners.push((ticket, winner));
+        }
+        winners
+    }
+
+    /// Sum of every player's weight in the current round.
+    pub(crate) fn total_twab_weight(&self) -> u128 {
+        let round = self.current_round();
+        self.twab_tree.total(round.id, round_seconds(&round))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_sdk::json_types::U128;
+    use std::collections::HashMap;
+
+    fn index(weights: &[(&str, u128)]) -> TicketIndex {
+        TicketIndex::new(
+            weights
+                .iter()
+                .map(|(name, tickets)| (account(name), *tickets)),
+        )
+    }
+
+    /// Pearson's chi-squared statistic of `counts` against `weights`.
+    fn chi_squared(
+        counts: &HashMap<AccountId, u64>,
+        weights: &[(&str, u128)],
+        samples: u64,
+    ) -> f64 {
+        let total: u128 = weights.iter().map(|(_, tickets)| tickets).sum();
+        weights
+            .iter()
+            .map(|(name, tickets)| {
+                let expected = samples as f64 * *tickets as f64 / total as f64;
+                let observed = *counts.get(&account(name)).unwrap_or(&0) as f64;
+                (observed - expected).powi(2) / expected
+            })
+            .sum()
+    }
+
+    #[test]
+    fn test_owner_of_maps_ticket_ranges() {
+        let tickets = index(&[("a.testnet", 1), ("b.testnet", 0), ("c.testnet", 3)]);
+        assert_eq!(tickets.total(), 4);
+        assert_eq!(tickets.owner_of(0), &account("a.testnet"));
+        assert_eq!(tickets.owner_of(1), &account("c.testnet"));
+        assert_eq!(tickets.owner_of(3), &account("c.testnet"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Ticket out of range")]
+    fn test_owner_of_rejects_ticket_past_end() {
+        index(&[("a.testnet", 2)]).owner_of(2);
+    }
+
+    #[test]
+    fn test_tickets_round_down_to_min_deposit() {
+        assert_eq!(tickets_for(MIN_DEPOSIT - 1, MIN_DEPOSIT), 0);
+        assert_eq!(tickets_for(3 * MIN_DEPOSIT + 7, MIN_DEPOSIT), 3);
+    }
+
+    #[test]
+    fn test_pick_is_proportional_to_tickets() {
+        let weights = [
+            ("a.testnet", 1),
+            ("b.testnet", 10),
+            ("c.testnet", 100),
+            ("d.testnet", 1000),
+        ];
+        let tickets = index(&weights);
+        let mut rng = rng_from_seed(&[42; 32]);
+        let samples = 200_000;
+        let mut counts = HashMap::new();
+        for _ in 0..samples {
+            *counts.entry(tickets.pick(&mut rng).clone()).or_insert(0) += 1;
+        }
+        // 3 degrees of freedom; 16.27 is the 0.1% critical value.
+        assert!(chi_squared(&counts, &weights, samples) < 16.27);
+    }
+
+    #[test]
+    fn test_contract_draws_weight_by_balance() {
+        let mut contract = setup();
+        let weights = [("small.testnet", 1), ("large.testnet", 9)];
+        for (name, tickets) in weights {
+            let amount = tickets * MIN_DEPOSIT;
+            set_context(name, amount);
+            contract.play(U128(amount));
+        }
+
+        let samples = 5_000u64;
+        let mut counts = HashMap::new();
+        for i in 0..samples {
+            let mut seed = [0u8; 32];
+            seed[..8].copy_from_slice(&i.to_le_bytes());
+            set_context("keeper.testnet", 0);
+            let (_, winner) = contract.select_winners(&seed, 1).remove(0);
+            *counts.entry(winner).or_insert(0) += 1;
+        }
+        // 1 degree of freedom; 10.83 is the 0.1% critical value.
+        assert!(chi_squared(&counts, &weights, samples) < 10.83);
+    }
+
+    #[test]
+    fn test_splitting_a_deposit_does_not_add_tickets() {
+        let mut contract = setup();
+        set_context("whale.testnet", 10 * MIN_DEPOSIT);
+        contract.play(U128(10 * MIN_DEPOSIT));
+        let whole = contract.total_twab_weight();
+
+        for i in 0..4 {
+            let name = format!("sybil{}.testnet", i);
+            set_context(&name, 5 * MIN_DEPOSIT / 2);
+            contract.play(U128(5 * MIN_DEPOSIT / 2));
+        }
+        let split = contract.total_twab_weight() - whole;
+        assert!(split <= whole);
+    }
+
+    #[test]
+    #[should_panic(expected = "No players to select a winner from")]
+    fn test_select_winner_needs_tickets() {
+        let contract = setup();
+        contract.select_winners(&[0; 32], 1);
+    }
+}
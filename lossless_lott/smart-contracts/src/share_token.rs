@@ -0,0 +1,480 @@
+//! Investor shares as a NEP-141 fungible token. Balances are the share
+//! counts in `investors`, so moving shares moves their claim on the vault
+//! with them and yield keeps accruing to whoever holds them. Accounts must
+//! be registered through NEP-145 storage management before they can receive
+//! shares; investing registers the investor for free.
+use near_contract_standards::fungible_token::core::FungibleTokenCore;
+use near_contract_standards::fungible_token::events::{FtBurn, FtTransfer};
+use near_contract_standards::fungible_token::metadata::{
+    FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC,
+};
+use near_contract_standards::fungible_token::receiver::ext_ft_receiver;
+use near_contract_standards::fungible_token::resolver::{ext_ft_resolver, FungibleTokenResolver};
+use near_contract_standards::storage_management::{
+    StorageBalance, StorageBalanceBounds, StorageManagement,
+};
+use near_sdk::json_types::U128;
+use near_sdk::{
+    assert_one_yocto, env, near_bindgen, AccountId, Balance, Gas, Promise, PromiseOrValue,
+    PromiseResult, StorageUsage,
+};
+
+use crate::{Contract, ContractExt, Ledger};
+
+const GAS_FOR_RESOLVE_TRANSFER: Gas = Gas(5_000_000_000_000);
+const GAS_FOR_FT_TRANSFER_CALL: Gas = Gas(25_000_000_000_000 + GAS_FOR_RESOLVE_TRANSFER.0);
+
+pub const SHARE_NAME: &str = "Lossless Lott investor share";
+pub const SHARE_SYMBOL: &str = "LLS";
+/// Shares start out worth one yoctoNEAR each, so they share NEAR's decimals.
+pub const SHARE_DECIMALS: u8 = 24;
+
+#[near_bindgen]
+impl FungibleTokenCore for Contract {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        self.transfer_shares(&sender_id, &receiver_id, amount.0, memo.as_deref());
+    }
+
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        assert_one_yocto();
+        assert!(
+            env::prepaid_gas() > GAS_FOR_FT_TRANSFER_CALL,
+            "More gas is required"
+        );
+        let sender_id = env::predecessor_account_id();
+        self.transfer_shares(&sender_id, &receiver_id, amount.0, memo.as_deref());
+        ext_ft_receiver::ext(receiver_id.clone())
+            .with_static_gas(env::prepaid_gas() - GAS_FOR_FT_TRANSFER_CALL)
+            .ft_on_transfer(sender_id.clone(), amount, msg)
+            .then(
+                ext_ft_resolver::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                    .ft_resolve_transfer(sender_id, receiver_id, amount),
+            )
+            .into()
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        U128(self.total_shares)
+    }
+
+    fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        U128(self.ledger_balance(&account_id, Ledger::Investor))
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenResolver for Contract {
+    /// Takes back whatever the receiver did not use, as far as they still
+    /// hold it, and returns the amount that stayed with them. Shares owed to
+    /// a sender who has since unregistered are burned.
+    #[private]
+    fn ft_resolve_transfer(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        let amount = amount.0;
+        let unused = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                match near_sdk::serde_json::from_slice::<U128>(&value) {
+                    Ok(unused) => std::cmp::min(amount, unused.0),
+                    Err(_) => amount,
+                }
+            }
+            _ => amount,
+        };
+        let refund = std::cmp::min(unused, self.ledger_balance(&receiver_id, Ledger::Investor));
+        if refund == 0 {
+            return U128(amount);
+        }
+
+        self.debit(&receiver_id, Ledger::Investor, refund);
+        if self.share_storage.contains_key(&sender_id) {
+            self.credit(&sender_id, Ledger::Investor, refund);
+            FtTransfer {
+                old_owner_id: &receiver_id,
+                new_owner_id: &sender_id,
+                amount: &U128(refund),
+                memo: Some("refund"),
+            }
+            .emit();
+            U128(amount - refund)
+        } else {
+            self.total_shares -= refund;
+            env::log_str("The account of the sender was deleted");
+            FtBurn {
+                owner_id: &receiver_id,
+                amount: &U128(refund),
+                memo: Some("refund"),
+            }
+            .emit();
+            U128(amount)
+        }
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenMetadataProvider for Contract {
+    fn ft_metadata(&self) -> FungibleTokenMetadata {
+        FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: SHARE_NAME.to_string(),
+            symbol: SHARE_SYMBOL.to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: SHARE_DECIMALS,
+        }
+    }
+}
+
+#[near_bindgen]
+impl StorageManagement for Contract {
+    /// Registers `account_id` to hold shares, refunding anything above the
+    /// minimum. `registration_only` changes nothing, as the only storage an
+    /// account needs is its registration.
+    #[payable]
+    #[allow(unused_variables)]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        let amount = env::attached_deposit();
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let refund = if self.share_storage.contains_key(&account_id) {
+            env::log_str("The account is already registered, refunding the deposit");
+            amount
+        } else {
+            let min = self.storage_balance_bounds().min.0;
+            assert!(
+                amount >= min,
+                "The attached deposit is less than the minimum storage balance"
+            );
+            self.share_storage.insert(&account_id, &min);
+            amount - min
+        };
+        if refund > 0 {
+            Promise::new(env::predecessor_account_id()).transfer(refund);
+        }
+        self.storage_balance_of(account_id).unwrap()
+    }
+
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let balance = self
+            .storage_balance_of(account_id.clone())
+            .unwrap_or_else(|| panic!("The account {} is not registered", account_id));
+        if let Some(amount) = amount {
+            assert!(
+                amount.0 == 0,
+                "The amount is greater than the available storage balance"
+            );
+        }
+        balance
+    }
+
+    /// Drops the caller's registration and refunds what they paid for it.
+    /// With `force`, any shares they still hold are burned and what they
+    /// were worth stays in the vault for the other investors.
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let deposit = match self.share_storage.get(&account_id) {
+            Some(deposit) => deposit,
+            None => {
+                env::log_str(&format!("The account {} is not registered", account_id));
+                return false;
+            }
+        };
+        let shares = self.ledger_balance(&account_id, Ledger::Investor);
+        if shares > 0 {
+            assert!(
+                force.unwrap_or(false),
+                "Can't unregister the account with the positive balance without force"
+            );
+            self.debit(&account_id, Ledger::Investor, shares);
+            self.total_shares -= shares;
+            FtBurn {
+                owner_id: &account_id,
+                amount: &U128(shares),
+                memo: Some("unregister"),
+            }
+            .emit();
+        }
+        self.share_storage.remove(&account_id);
+        Promise::new(account_id).transfer(deposit + 1);
+        true
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        let min = Balance::from(self.share_storage_usage) * env::storage_byte_cost();
+        StorageBalanceBounds {
+            min: U128(min),
+            max: Some(U128(min)),
+        }
+    }
+
+    /// Investors registered by investing paid nothing and have a zero total.
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.share_storage
+            .get(&account_id)
+            .map(|deposit| StorageBalance {
+                total: U128(deposit),
+                available: U128(0),
+            })
+    }
+}
+
+impl Contract {
+    /// Registers an investor who received shares without paying for storage.
+    pub(crate) fn register_share_holder(&mut self, account_id: &AccountId) {
+        if !self.share_storage.contains_key(account_id) {
+            self.share_storage.insert(account_id, &0);
+        }
+    }
+
+    /// Bytes one registered holder takes up, measured with the longest
+    /// possible account id.
+    pub(crate) fn measure_share_storage_usage(&mut self) -> StorageUsage {
+        let initial = env::storage_usage();
+        let account_id = AccountId::new_unchecked("a".repeat(64));
+        self.share_storage.insert(&account_id, &0);
+        self.investors.insert(&account_id, &0);
+        let usage = env::storage_usage() - initial;
+        self.investors.remove(&account_id);
+        self.share_storage.remove(&account_id);
+        usage
+    }
+
+    fn transfer_shares(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        amount: Balance,
+        memo: Option<&str>,
+    ) {
+        assert_ne!(
+            sender_id, receiver_id,
+            "Sender and receiver should be different"
+        );
+        assert!(amount > 0, "The amount should be a positive number");
+        assert!(
+            self.share_storage.contains_key(receiver_id),
+            "The account {} is not registered",
+            receiver_id
+        );
+        assert!(
+            amount <= self.ledger_balance(sender_id, Ledger::Investor),
+            "The account doesn't have enough balance"
+        );
+        self.debit(sender_id, Ledger::Investor, amount);
+        self.credit(receiver_id, Ledger::Investor, amount);
+        FtTransfer {
+            old_owner_id: sender_id,
+            new_owner_id: receiver_id,
+            amount: &U128(amount),
+            memo,
+        }
+        .emit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_sdk::test_utils::{get_created_receipts, get_logs};
+
+    /// Alice holds 100 shares worth 120 after a round of yield.
+    fn setup_shares() -> Contract {
+        let mut contract = setup();
+        set_context("alice.testnet", 100 * MIN_DEPOSIT);
+        contract.invest(U128(100 * MIN_DEPOSIT));
+        contract.accrue_investor_yield(20 * MIN_DEPOSIT);
+        contract
+    }
+
+    fn register(contract: &mut Contract, name: &str) {
+        let min = contract.storage_balance_bounds().min.0;
+        set_context(name, min);
+        contract.storage_deposit(None, None);
+    }
+
+    #[test]
+    fn test_investing_mints_and_registers() {
+        let contract = setup_shares();
+        assert_eq!(contract.ft_total_supply(), U128(100 * MIN_DEPOSIT));
+        assert_eq!(
+            contract.ft_balance_of(account("alice.testnet")),
+            U128(100 * MIN_DEPOSIT)
+        );
+        let storage = contract
+            .storage_balance_of(account("alice.testnet"))
+            .unwrap();
+        assert_eq!(storage.total, U128(0));
+    }
+
+    #[test]
+    fn test_transfer_moves_claim_on_vault() {
+        let mut contract = setup_shares();
+        register(&mut contract, "bob.testnet");
+        set_context("alice.testnet", 1);
+        contract.ft_transfer(account("bob.testnet"), U128(50 * MIN_DEPOSIT), None);
+
+        assert!(get_logs()
+            .iter()
+            .any(|log| log.contains("\"event\":\"ft_transfer\"")));
+        let bob = contract.get_investor_assets(account("bob.testnet")).0;
+        let alice = contract.get_investor_assets(account("alice.testnet")).0;
+        assert!(bob > 60 * MIN_DEPOSIT - 2 && bob <= 60 * MIN_DEPOSIT);
+        assert!(alice > 60 * MIN_DEPOSIT - 2 && alice <= 60 * MIN_DEPOSIT);
+
+        // Yield after the transfer accrues to the new holder.
+        contract.accrue_investor_yield(12 * MIN_DEPOSIT);
+        let bob_after = contract.get_investor_assets(account("bob.testnet")).0;
+        assert!(bob_after >= bob + 6 * MIN_DEPOSIT - 1);
+        assert_eq!(contract.ft_total_supply(), U128(100 * MIN_DEPOSIT));
+    }
+
+    #[test]
+    fn test_received_shares_can_be_redeemed() {
+        let mut contract = setup_shares();
+        register(&mut contract, "bob.testnet");
+        set_context("alice.testnet", 1);
+        contract.ft_transfer(account("bob.testnet"), U128(50 * MIN_DEPOSIT), None);
+
+        set_context("bob.testnet", 0);
+        let assets = contract.redeem(U128(50 * MIN_DEPOSIT));
+        assert_eq!(
+            contract.get_pending_withdrawals(account("bob.testnet"))[0].amount,
+            assets
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The account bob.testnet is not registered")]
+    fn test_transfer_needs_registered_receiver() {
+        let mut contract = setup_shares();
+        set_context("alice.testnet", 1);
+        contract.ft_transfer(account("bob.testnet"), U128(MIN_DEPOSIT), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_transfer_needs_one_yocto() {
+        let mut contract = setup_shares();
+        register(&mut contract, "bob.testnet");
+        set_context("alice.testnet", 0);
+        contract.ft_transfer(account("bob.testnet"), U128(MIN_DEPOSIT), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "The account doesn't have enough balance")]
+    fn test_cannot_transfer_more_than_held() {
+        let mut contract = setup_shares();
+        register(&mut contract, "bob.testnet");
+        set_context("alice.testnet", 1);
+        contract.ft_transfer(account("bob.testnet"), U128(100 * MIN_DEPOSIT + 1), None);
+    }
+
+    #[test]
+    fn test_resolve_refunds_unused_shares() {
+        let mut contract = setup_shares();
+        register(&mut contract, "dex.testnet");
+        set_context("alice.testnet", 1);
+        contract.ft_transfer(account("dex.testnet"), U128(10 * MIN_DEPOSIT), None);
+
+        set_callback_context(vec![PromiseResult::Successful(
+            format!("\"{}\"", 4 * MIN_DEPOSIT).into_bytes(),
+        )]);
+        let used = contract.ft_resolve_transfer(
+            account("alice.testnet"),
+            account("dex.testnet"),
+            U128(10 * MIN_DEPOSIT),
+        );
+        assert_eq!(used, U128(6 * MIN_DEPOSIT));
+        assert_eq!(
+            contract.ft_balance_of(account("alice.testnet")),
+            U128(94 * MIN_DEPOSIT)
+        );
+        assert_eq!(
+            contract.ft_balance_of(account("dex.testnet")),
+            U128(6 * MIN_DEPOSIT)
+        );
+    }
+
+    #[test]
+    fn test_storage_deposit_refunds_excess() {
+        let mut contract = setup();
+        let min = contract.storage_balance_bounds().min.0;
+        assert!(min > 0);
+        set_context("bob.testnet", min + 5);
+        let balance = contract.storage_deposit(None, None);
+        assert_eq!(balance.total, U128(min));
+        assert!(get_created_receipts().iter().any(|receipt| {
+            receipt.receiver_id == account("bob.testnet")
+                && matches!(
+                    receipt.actions[..],
+                    [near_sdk::mock::VmAction::Transfer { deposit: 5 }]
+                )
+        }));
+    }
+
+    #[test]
+    #[should_panic(expected = "The attached deposit is less than the minimum storage balance")]
+    fn test_storage_deposit_needs_minimum() {
+        let mut contract = setup();
+        set_context("bob.testnet", 1);
+        contract.storage_deposit(None, None);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Can't unregister the account with the positive balance without force"
+    )]
+    fn test_unregister_with_shares_needs_force() {
+        let mut contract = setup_shares();
+        set_context("alice.testnet", 1);
+        contract.storage_unregister(None);
+    }
+
+    #[test]
+    fn test_forced_unregister_leaves_value_in_vault() {
+        let mut contract = setup_shares();
+        register(&mut contract, "bob.testnet");
+        set_context("alice.testnet", 1);
+        contract.ft_transfer(account("bob.testnet"), U128(50 * MIN_DEPOSIT), None);
+
+        set_context("alice.testnet", 1);
+        assert!(contract.storage_unregister(Some(true)));
+        assert!(contract
+            .storage_balance_of(account("alice.testnet"))
+            .is_none());
+        assert_eq!(contract.ft_total_supply(), U128(50 * MIN_DEPOSIT));
+        assert!(contract.get_investor_assets(account("bob.testnet")).0 >= 120 * MIN_DEPOSIT - 2);
+    }
+
+    #[test]
+    fn test_metadata() {
+        let contract = setup();
+        let metadata = contract.ft_metadata();
+        assert_eq!(metadata.spec, FT_METADATA_SPEC);
+        assert_eq!(metadata.decimals, 24);
+    }
+}
@@ -0,0 +1,245 @@
+//! Splitting each round's yield between the prize pool, investors, the
+//! protocol and a reserve fund. Only newly earned yield is split; a jackpot
+//! carried over from earlier rounds already went through the split once.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
+use near_sdk::{near_bindgen, Balance};
+
+use crate::events;
+use crate::prize::BASIS_POINTS;
+use crate::{Contract, ContractExt};
+
+/// Shares of each round's new yield, in basis points.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub struct YieldSplit {
+    pub prize_pool_bps: u32,
+    pub investor_bps: u32,
+    pub protocol_bps: u32,
+    pub reserve_bps: u32,
+}
+
+impl Default for YieldSplit {
+    /// Everything goes to the prize pool.
+    fn default() -> Self {
+        Self {
+            prize_pool_bps: BASIS_POINTS,
+            investor_bps: 0,
+            protocol_bps: 0,
+            reserve_bps: 0,
+        }
+    }
+}
+
+/// What the contract holds in each bucket. The prize pool is the jackpot
+/// plus prizes not yet claimed; the investor return is everything paid into
+/// the investor vault so far.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct YieldBuckets {
+    pub prize_pool: U128,
+    pub investor_return: U128,
+    pub protocol_fees: U128,
+    pub reserve: U128,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Replaces the split applied from the next settlement on.
+    pub fn set_yield_split(&mut self, split: YieldSplit) {
+        self.assert_owner();
+        let total = split.prize_pool_bps as u64
+            + split.investor_bps as u64
+            + split.protocol_bps as u64
+            + split.reserve_bps as u64;
+        assert_eq!(
+            total, BASIS_POINTS as u64,
+            "Yield split must add up to {} basis points",
+            BASIS_POINTS
+        );
+        self.yield_split = split;
+    }
+
+    pub fn get_yield_split(&self) -> YieldSplit {
+        self.yield_split
+    }
+
+    pub fn get_yield_buckets(&self) -> YieldBuckets {
+        YieldBuckets {
+            prize_pool: U128(self.jackpot + self.total_prizes),
+            investor_return: U128(self.investor_return),
+            protocol_fees: U128(self.protocol_fees),
+            reserve: U128(self.reserve_fund),
+        }
+    }
+}
+
+impl Contract {
+    /// Takes the investor, protocol and reserve shares out of the yield
+    /// measured for `round_id` and returns what is left for the prize.
+    /// Rounding dust stays in the prize, and so does the investor share
+    /// while the vault has no shares for it to accrue to.
+    pub(crate) fn split_yield(&mut self, round_id: u64, measured: Balance) -> Balance {
+        let earned = measured - std::cmp::min(self.jackpot, measured);
+        let share = |bps: u32| earned * bps as u128 / BASIS_POINTS as u128;
+        let investor = if self.total_shares == 0 {
+            0
+        } else {
+            share(self.yield_split.investor_bps)
+        };
+        let protocol = share(self.yield_split.protocol_bps);
+        let reserve = share(self.yield_split.reserve_bps);
+        self.investor_return += investor;
+        self.accrue_investor_yield(investor);
+        self.protocol_fees += protocol;
+        self.reserve_fund += reserve;
+
+        let prize = measured - investor - protocol - reserve;
+        events::emit(
+            "yield_split",
+            json!({
+                "round_id": round_id,
+                "earned": U128(earned),
+                "prize_pool": U128(prize),
+                "investor_return": U128(investor),
+                "protocol_fees": U128(protocol),
+                "reserve": U128(reserve),
+            }),
+        );
+        prize
+    }
+
+    /// Yield set aside for the protocol and the reserve. Like principal, it
+    /// is owed and never counts towards a prize. The investor share is
+    /// already part of `total_stake`.
+    pub(crate) fn yield_buckets_total(&self) -> Balance {
+        self.protocol_fees + self.reserve_fund
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::round::DEFAULT_ROUND_DURATION;
+    use crate::test_utils::*;
+
+    fn split(
+        prize_pool_bps: u32,
+        investor_bps: u32,
+        protocol_bps: u32,
+        reserve_bps: u32,
+    ) -> YieldSplit {
+        YieldSplit {
+            prize_pool_bps,
+            investor_bps,
+            protocol_bps,
+            reserve_bps,
+        }
+    }
+
+    /// An invested pool with the given split and the first round over.
+    fn setup_split(players: &[&str]) -> Contract {
+        let mut contract = setup();
+        set_context("owner.testnet", 0);
+        contract.set_yield_split(split(7_000, 2_000, 500, 500));
+        set_context("alice.testnet", 100 * MIN_DEPOSIT);
+        contract.invest(U128(100 * MIN_DEPOSIT));
+        for &name in players {
+            set_context(name, MIN_DEPOSIT);
+            contract.play(U128(MIN_DEPOSIT));
+        }
+        contract.sync_stake();
+        set_context_at("keeper.testnet", 0, DEFAULT_ROUND_DURATION, 7);
+        contract
+    }
+
+    #[test]
+    fn test_settlement_splits_yield() {
+        let mut contract = setup_split(&["bob.testnet"]);
+        contract.draw();
+        let round = settle(&mut contract, 110 * MIN_DEPOSIT, DEFAULT_ROUND_DURATION);
+
+        assert_eq!(round.prize, U128(7 * MIN_DEPOSIT));
+        assert_eq!(
+            contract.get_yield_buckets(),
+            YieldBuckets {
+                prize_pool: U128(7 * MIN_DEPOSIT),
+                investor_return: U128(2 * MIN_DEPOSIT),
+                protocol_fees: U128(MIN_DEPOSIT / 2),
+                reserve: U128(MIN_DEPOSIT / 2),
+            }
+        );
+        assert_eq!(contract.principal_in_pool(), U128(110 * MIN_DEPOSIT));
+    }
+
+    #[test]
+    fn test_rounding_dust_stays_in_prize() {
+        let mut contract = setup_split(&["bob.testnet"]);
+        contract.draw();
+        let round = settle(&mut contract, 100 * MIN_DEPOSIT + 3, DEFAULT_ROUND_DURATION);
+        assert_eq!(round.prize, U128(3));
+        assert_eq!(contract.yield_buckets_total(), 0);
+    }
+
+    #[test]
+    fn test_jackpot_is_not_split_twice() {
+        let mut contract = setup_split(&[]);
+        contract.draw();
+        settle(&mut contract, 110 * MIN_DEPOSIT, DEFAULT_ROUND_DURATION);
+        assert_eq!(contract.get_jackpot(), U128(7 * MIN_DEPOSIT));
+
+        set_context_at("bob.testnet", MIN_DEPOSIT, DEFAULT_ROUND_DURATION, 7);
+        contract.play(U128(MIN_DEPOSIT));
+        set_context_at("keeper.testnet", 0, 2 * DEFAULT_ROUND_DURATION, 14);
+        contract.draw();
+        let round = settle(&mut contract, 120 * MIN_DEPOSIT, 2 * DEFAULT_ROUND_DURATION);
+
+        // 7 carried over plus 70% of the 10 newly earned.
+        assert_eq!(round.prize, U128(14 * MIN_DEPOSIT));
+        assert_eq!(round.jackpot, U128(7 * MIN_DEPOSIT));
+        assert_eq!(contract.investor_return, 4 * MIN_DEPOSIT);
+    }
+
+    #[test]
+    fn test_investor_share_goes_to_prize_without_investors() {
+        let mut contract = setup();
+        set_context("owner.testnet", 0);
+        contract.set_yield_split(split(7_000, 2_000, 500, 500));
+        set_context("bob.testnet", 100 * MIN_DEPOSIT);
+        contract.play(U128(100 * MIN_DEPOSIT));
+        contract.sync_stake();
+        set_context_at("keeper.testnet", 0, DEFAULT_ROUND_DURATION, 7);
+        contract.draw();
+        // 90 of the 100 deposited is staked; the rest is the buffer.
+        let round = settle(&mut contract, 100 * MIN_DEPOSIT, DEFAULT_ROUND_DURATION);
+
+        assert_eq!(round.prize, U128(9 * MIN_DEPOSIT));
+        assert_eq!(contract.investor_return, 0);
+        assert_eq!(contract.total_assets(), U128(0));
+
+        set_context("alice.testnet", MIN_DEPOSIT);
+        contract.invest(U128(MIN_DEPOSIT));
+        assert_eq!(contract.total_assets(), U128(MIN_DEPOSIT));
+        assert_eq!(contract.get_total_shares(), U128(MIN_DEPOSIT));
+    }
+
+    #[test]
+    #[should_panic(expected = "Yield split must add up to 10000 basis points")]
+    fn test_split_must_cover_whole_yield() {
+        let mut contract = setup();
+        set_context("owner.testnet", 0);
+        contract.set_yield_split(split(7_000, 2_000, 500, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_split_is_owner_only() {
+        let mut contract = setup();
+        set_context("bob.testnet", 0);
+        contract.set_yield_split(YieldSplit::default());
+    }
+}
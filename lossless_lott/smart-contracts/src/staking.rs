@@ -0,0 +1,401 @@
+//! Calls into the standard NEAR staking pools the lottery delegates its funds
+//! to, and the callbacks that resolve them.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, ext_contract, near_bindgen, AccountId, Balance, Gas, Promise, PromiseError};
+
+use crate::{is_promise_success, Contract, ContractExt, Ledger, GAS_FOR_ON_WITHDRAW_TRANSFER};
+
+pub(crate) const TGAS: u64 = 1_000_000_000_000;
+
+pub const GAS_FOR_DEPOSIT_AND_STAKE: Gas = Gas(50 * TGAS);
+pub const GAS_FOR_UNSTAKE: Gas = Gas(50 * TGAS);
+pub const GAS_FOR_WITHDRAW: Gas = Gas(50 * TGAS);
+pub const GAS_FOR_VIEW: Gas = Gas(5 * TGAS);
+
+const GAS_FOR_ON_POOL_BALANCE: Gas = Gas(10 * TGAS);
+pub const GAS_FOR_ON_STAKE: Gas = Gas(10 * TGAS);
+pub const GAS_FOR_ON_UNSTAKE: Gas = Gas(10 * TGAS);
+const GAS_FOR_ON_POOL_WITHDRAW: Gas = Gas(10 * TGAS + GAS_FOR_ON_WITHDRAW_TRANSFER.0);
+const GAS_FOR_ON_UNSTAKED_AVAILABLE: Gas =
+    Gas(10 * TGAS + GAS_FOR_WITHDRAW.0 + GAS_FOR_ON_POOL_WITHDRAW.0);
+
+/// Gas `complete_withdrawal` needs to reach the final transfer.
+pub const GAS_FOR_COMPLETE_WITHDRAWAL: Gas = Gas(GAS_FOR_VIEW.0 + GAS_FOR_ON_UNSTAKED_AVAILABLE.0);
+
+/// What the staking pool last reported holding on behalf of this contract.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolBalance {
+    pub staked: U128,
+    pub unstaked: U128,
+    pub unstaked_available: bool,
+    pub epoch_height: u64,
+}
+
+impl Default for PoolBalance {
+    fn default() -> Self {
+        Self {
+            staked: U128(0),
+            unstaked: U128(0),
+            unstaked_available: false,
+            epoch_height: 0,
+        }
+    }
+}
+
+/// The subset of the core-contracts `staking-pool` interface the lottery uses.
+#[allow(dead_code)]
+#[ext_contract(ext_staking_contract)]
+pub trait StakingContract {
+    fn deposit_and_stake(&mut self);
+    fn unstake(&mut self, amount: U128);
+    fn withdraw(&mut self, amount: U128);
+    fn get_account_staked_balance(&self, account_id: AccountId) -> U128;
+    fn get_account_unstaked_balance(&self, account_id: AccountId) -> U128;
+    fn is_account_unstaked_balance_available(&self, account_id: AccountId) -> bool;
+    fn get_account_total_balance(&self, account_id: AccountId) -> U128;
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Asks a staking pool for this contract's staked and unstaked balances
+    /// and caches the answer for `get_pool_balance`.
+    pub fn refresh_pool_balance(&mut self, pool_id: AccountId) -> Promise {
+        assert!(
+            self.find_staking_pool(&pool_id).is_some(),
+            "Unknown staking pool {}",
+            pool_id
+        );
+        assert_enough_gas(Gas(3 * GAS_FOR_VIEW.0 + GAS_FOR_ON_POOL_BALANCE.0));
+        let account_id = env::current_account_id();
+        let pool = || ext_staking_contract::ext(pool_id.clone()).with_static_gas(GAS_FOR_VIEW);
+        pool()
+            .get_account_staked_balance(account_id.clone())
+            .and(pool().get_account_unstaked_balance(account_id.clone()))
+            .and(pool().is_account_unstaked_balance_available(account_id))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_POOL_BALANCE)
+                    .on_pool_balance(pool_id.clone()),
+            )
+    }
+
+    #[private]
+    pub fn on_pool_balance(
+        &mut self,
+        pool_id: AccountId,
+        #[callback_unwrap] staked: U128,
+        #[callback_unwrap] unstaked: U128,
+        #[callback_unwrap] unstaked_available: bool,
+    ) -> PoolBalance {
+        let balance = PoolBalance {
+            staked,
+            unstaked,
+            unstaked_available,
+            epoch_height: env::epoch_height(),
+        };
+        if let Some(pool) = self.find_staking_pool(&pool_id) {
+            pool.balance = balance.clone();
+        }
+        balance
+    }
+
+    pub fn get_pool_balance(&self, pool_id: AccountId) -> Option<PoolBalance> {
+        self.staking_pools
+            .iter()
+            .find(|pool| pool.account_id == pool_id)
+            .map(|pool| pool.balance.clone())
+    }
+
+    /// Withdraws the account's unstaked funds from the pool if the pool
+    /// agrees they have been released, otherwise puts the request back.
+    #[private]
+    pub fn on_unstaked_balance_available(
+        &mut self,
+        account_id: AccountId,
+        ledger: Ledger,
+        pool_id: AccountId,
+        amount: U128,
+        #[callback_result] available: Result<bool, PromiseError>,
+    ) {
+        if available != Ok(true) {
+            env::log_str("Unstaked balance is not yet available in the staking pool");
+            self.restore_withdrawal(&account_id, ledger, amount.0, 0);
+            return;
+        }
+        ext_staking_contract::ext(pool_id.clone())
+            .with_static_gas(GAS_FOR_WITHDRAW)
+            .withdraw(amount)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_POOL_WITHDRAW)
+                    .on_pool_withdraw(account_id, ledger, pool_id, amount),
+            );
+    }
+
+    #[private]
+    pub fn on_pool_withdraw(
+        &mut self,
+        account_id: AccountId,
+        ledger: Ledger,
+        pool_id: AccountId,
+        amount: U128,
+    ) {
+        if is_promise_success() {
+            if let Some(pool) = self.find_staking_pool(&pool_id) {
+                pool.unstaked.0 = pool.unstaked.0.saturating_sub(amount.0);
+                pool.total_withdrawn.0 += amount.0;
+            }
+            self.total_unstaking -= amount.0;
+            self.transfer_to(account_id, ledger, amount.0);
+        } else {
+            self.restore_withdrawal(&account_id, ledger, amount.0, 0);
+        }
+    }
+}
+
+impl Contract {
+    /// Stakes `amount` in `pool_id` and hands the outcome to `callback`,
+    /// which gets `GAS_FOR_ON_STAKE`.
+    pub(crate) fn stake_then(
+        &self,
+        pool_id: &AccountId,
+        amount: Balance,
+        callback: Promise,
+    ) -> Promise {
+        ext_staking_contract::ext(pool_id.clone())
+            .with_attached_deposit(amount)
+            .with_static_gas(GAS_FOR_DEPOSIT_AND_STAKE)
+            .deposit_and_stake()
+            .then(callback)
+    }
+
+    /// Unstakes `amount` from `pool_id` and hands the outcome to `callback`,
+    /// which gets `GAS_FOR_ON_UNSTAKE`.
+    pub(crate) fn unstake_then(
+        &self,
+        pool_id: &AccountId,
+        amount: Balance,
+        callback: Promise,
+    ) -> Promise {
+        ext_staking_contract::ext(pool_id.clone())
+            .with_static_gas(GAS_FOR_UNSTAKE)
+            .unstake(U128(amount))
+            .then(callback)
+    }
+
+    /// Checks with each pool holding part of `amount` that its unstaked
+    /// funds are released before withdrawing them for `account_id`, who is
+    /// paid out of `ledger`. What no pool holds yet is put back to be
+    /// completed later.
+    pub(crate) fn withdraw_unstaked(
+        &mut self,
+        account_id: AccountId,
+        ledger: Ledger,
+        amount: Balance,
+    ) {
+        let (parts, missing) = self.split_withdrawal(amount);
+        if missing > 0 {
+            env::log_str(&format!(
+                "The staking pools do not hold {} of the withdrawal yet",
+                missing
+            ));
+            self.restore_withdrawal(&account_id, ledger, missing, 0);
+        }
+        assert_enough_gas(Gas(parts.len() as u64 * GAS_FOR_COMPLETE_WITHDRAWAL.0));
+        for (pool_id, part) in parts {
+            ext_staking_contract::ext(pool_id.clone())
+                .with_static_gas(GAS_FOR_VIEW)
+                .is_account_unstaked_balance_available(env::current_account_id())
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(GAS_FOR_ON_UNSTAKED_AVAILABLE)
+                        .on_unstaked_balance_available(
+                            account_id.clone(),
+                            ledger,
+                            pool_id,
+                            U128(part),
+                        ),
+                );
+        }
+    }
+}
+
+/// Fails early, before any state is touched, if the caller did not attach
+/// enough gas for the cross-contract calls that follow.
+pub(crate) fn assert_enough_gas(required: Gas) {
+    assert!(
+        env::prepaid_gas() - env::used_gas() >= required,
+        "Not enough gas attached, {} TGas required",
+        required.0 / TGAS
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::PendingWithdrawal;
+    use near_sdk::PromiseResult;
+
+    #[test]
+    fn test_sync_stakes_invested_amount() {
+        let mut contract = setup();
+        set_context("alice.testnet", 5 * MIN_DEPOSIT);
+        contract.invest(U128(5 * MIN_DEPOSIT));
+        assert!(function_calls().is_empty());
+        contract.sync_stake();
+
+        assert_eq!(
+            function_calls()[0],
+            (
+                account("staking.testnet"),
+                "deposit_and_stake".to_string(),
+                5 * MIN_DEPOSIT
+            )
+        );
+    }
+
+    #[test]
+    fn test_investor_withdraw_unstakes_from_pool() {
+        let mut contract = setup();
+        set_context("alice.testnet", 5 * MIN_DEPOSIT);
+        contract.invest(U128(5 * MIN_DEPOSIT));
+        contract.sync_stake();
+        set_context_at("alice.testnet", 0, 0, 1);
+        contract.withdraw(Ledger::Investor, U128(MIN_DEPOSIT));
+        contract.sync_stake();
+
+        assert_eq!(
+            function_calls()[0],
+            (account("staking.testnet"), "unstake".to_string(), 0)
+        );
+    }
+
+    #[test]
+    fn test_complete_withdrawal_checks_pool_availability() {
+        let mut contract = setup();
+        set_context("alice.testnet", MIN_DEPOSIT);
+        contract.invest(U128(MIN_DEPOSIT));
+        contract.sync_stake();
+        set_context_at("alice.testnet", 0, 0, 1);
+        contract.withdraw_all(Ledger::Investor);
+        contract.sync_stake();
+
+        set_context_at("alice.testnet", 0, 0, 1 + crate::UNSTAKE_DELAY_EPOCHS);
+        contract.complete_withdrawal();
+        let calls = function_calls();
+        assert_eq!(calls[0].1, "is_account_unstaked_balance_available");
+        assert_eq!(calls[1].1, "on_unstaked_balance_available");
+    }
+
+    #[test]
+    fn test_unavailable_unstaked_balance_restores_pending_withdrawal() {
+        let mut contract = setup();
+        set_callback_context(vec![PromiseResult::Successful(b"false".to_vec())]);
+        contract.on_unstaked_balance_available(
+            account("alice.testnet"),
+            Ledger::Investor,
+            account("staking.testnet"),
+            U128(MIN_DEPOSIT),
+            Ok(false),
+        );
+        assert_eq!(
+            contract.get_pending_withdrawals(account("alice.testnet")),
+            vec![PendingWithdrawal {
+                amount: U128(MIN_DEPOSIT),
+                available_epoch: 0,
+                unstaked: true,
+            }]
+        );
+        assert!(function_calls().is_empty());
+    }
+
+    #[test]
+    fn test_available_unstaked_balance_is_withdrawn_from_pool() {
+        let mut contract = setup();
+        set_callback_context(vec![PromiseResult::Successful(b"true".to_vec())]);
+        contract.on_unstaked_balance_available(
+            account("alice.testnet"),
+            Ledger::Investor,
+            account("staking.testnet"),
+            U128(MIN_DEPOSIT),
+            Ok(true),
+        );
+        let calls = function_calls();
+        assert_eq!(
+            calls[0],
+            (account("staking.testnet"), "withdraw".to_string(), 0)
+        );
+        assert_eq!(calls[1].1, "on_pool_withdraw");
+    }
+
+    #[test]
+    fn test_failed_pool_withdraw_restores_pending_withdrawal() {
+        let mut contract = setup();
+        set_callback_context(vec![PromiseResult::Failed]);
+        contract.on_pool_withdraw(
+            account("alice.testnet"),
+            Ledger::Investor,
+            account("staking.testnet"),
+            U128(MIN_DEPOSIT),
+        );
+        assert_eq!(
+            contract.get_pending_withdrawals(account("alice.testnet"))[0].amount,
+            U128(MIN_DEPOSIT)
+        );
+    }
+
+    #[test]
+    fn test_on_pool_balance_caches_pool_view() {
+        let mut contract = setup();
+        set_callback_context(vec![
+            PromiseResult::Successful(b"\"700\"".to_vec()),
+            PromiseResult::Successful(b"\"300\"".to_vec()),
+            PromiseResult::Successful(b"true".to_vec()),
+        ]);
+        contract.on_pool_balance(account("staking.testnet"), U128(700), U128(300), true);
+        assert_eq!(
+            contract.get_pool_balance(account("staking.testnet")),
+            Some(PoolBalance {
+                staked: U128(700),
+                unstaked: U128(300),
+                unstaked_available: true,
+                epoch_height: 0,
+            })
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough gas attached")]
+    fn test_sync_requires_gas_for_staking() {
+        let mut contract = setup();
+        set_context("alice.testnet", MIN_DEPOSIT);
+        contract.invest(U128(MIN_DEPOSIT));
+        let context = near_sdk::test_utils::VMContextBuilder::new()
+            .current_account_id(account(CONTRACT_ID))
+            .predecessor_account_id(account("keeper.testnet"))
+            .prepaid_gas(Gas(10 * TGAS))
+            .build();
+        near_sdk::testing_env!(context);
+        contract.sync_stake();
+    }
+
+    #[test]
+    fn test_withdraw_promises_carry_rollback_callbacks() {
+        let mut contract = setup();
+        set_context("alice.testnet", MIN_DEPOSIT);
+        contract.invest(U128(MIN_DEPOSIT));
+        contract.sync_stake();
+        let calls = function_calls();
+        assert_eq!(calls[1].1, "on_sync_stake");
+
+        set_context_at("alice.testnet", 0, 0, 1);
+        contract.withdraw_all(Ledger::Investor);
+        contract.sync_stake();
+        let calls = function_calls();
+        assert_eq!(calls[1].1, "on_sync_unstake");
+    }
+}
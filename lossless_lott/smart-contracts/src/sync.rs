@@ -0,0 +1,484 @@
+//! Epoch-batched staking. Deposits wait in `pending_stake` instead of each
+//! making its own call to the pool, and once an epoch a keeper's
+//! `sync_stake` nets them against the withdrawal batches that are due. The
+//! part of the deposits that covers withdrawals moves into the withdrawal
+//! reserve and never reaches the pools; only the difference is staked or
+//! unstaked, with one call per staking pool. Withdrawals the pools cannot
+//! take yet, because they still hold earlier unstaked funds, stay due for a
+//! later sync.
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId, Balance, Gas, PromiseError};
+
+use crate::staking::{
+    assert_enough_gas, ext_staking_contract, GAS_FOR_DEPOSIT_AND_STAKE, GAS_FOR_ON_STAKE,
+    GAS_FOR_ON_UNSTAKE, GAS_FOR_UNSTAKE, GAS_FOR_VIEW, TGAS,
+};
+use crate::{is_promise_success, Contract, ContractExt, UNSTAKE_DELAY_EPOCHS};
+
+const GAS_FOR_ON_DRAIN_BALANCE: Gas = Gas(10 * TGAS + GAS_FOR_UNSTAKE.0 + GAS_FOR_ON_UNSTAKE.0);
+
+/// What the next `sync_stake` has to settle.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakeSync {
+    pub pending_stake: U128,
+    /// Withdrawals in batches that are due, and unstakes a pool refused.
+    pub pending_unstake: U128,
+    /// Withdrawals already covered by deposits, held by the contract.
+    pub withdrawal_reserve: U128,
+    pub last_sync_epoch: Option<u64>,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Nets pending deposits against due withdrawals and stakes or unstakes
+    /// the difference, split across the staking pools, along with the
+    /// drain of any removed pool. Anyone may call it, once per epoch.
+    pub fn sync_stake(&mut self) {
+        let epoch = env::epoch_height();
+        assert!(
+            self.last_sync_epoch != Some(epoch),
+            "Stake already synced in epoch {}",
+            epoch
+        );
+        let deposits = self.pending_stake;
+        let shortfall = self.unstake_shortfall;
+        let (due, batches) = self.due_batches();
+        let withdrawals = batches + shortfall;
+        let reopened = std::mem::take(&mut self.shortfall_batches);
+        let stakes = match deposits > withdrawals {
+            true => self.split_stake(deposits - withdrawals),
+            false => vec![],
+        };
+        let (mut unstakes, unplaced) = self.split_unstake(withdrawals.saturating_sub(deposits));
+        let deferred = unplaced > 0;
+        if deferred {
+            unstakes = self.split_unstake(0).0;
+        }
+        assert!(
+            deposits > 0 || withdrawals > 0 || !unstakes.is_empty(),
+            "Nothing to stake or unstake"
+        );
+        assert_enough_gas(Gas(stakes.len() as u64
+            * (GAS_FOR_DEPOSIT_AND_STAKE.0 + GAS_FOR_ON_STAKE.0)
+            + unstakes.len() as u64 * (GAS_FOR_VIEW.0 + GAS_FOR_ON_DRAIN_BALANCE.0)));
+        self.last_sync_epoch = Some(epoch);
+
+        let netted = std::cmp::min(deposits, withdrawals);
+        self.pending_stake = 0;
+        // Deposits cover the refused unstakes first, being older.
+        let shortfall_netted = std::cmp::min(netted, shortfall);
+        let left = self.reserve_batches(&reopened, shortfall_netted);
+        self.reserve_batches(&due, netted - shortfall_netted + left);
+        self.unstake_shortfall = 0;
+        let mut closing = vec![];
+        if deferred {
+            // Only what deposits fully cover is settled; the rest waits for
+            // the pools to be withdrawn from.
+            self.unstake_shortfall = shortfall - shortfall_netted;
+            match self.unstake_shortfall {
+                0 => closing.extend(reopened),
+                _ => self.shortfall_batches = reopened,
+            }
+            closing.extend(due.into_iter().filter(|e| self.batch_reserved(*e)));
+            env::log_str(&format!(
+                "Deferred unstaking {}: the staking pools still hold unstaked funds",
+                withdrawals - netted
+            ));
+        } else {
+            closing.extend(reopened);
+            closing.extend(due);
+        }
+        self.close_batches(&closing);
+        env::log_str(&format!(
+            "Synced stake: {} deposited, {} withdrawn, {} netted",
+            deposits,
+            match deferred {
+                true => netted,
+                false => withdrawals,
+            },
+            netted
+        ));
+
+        for (pool_id, amount) in stakes {
+            let pool = self.find_staking_pool(&pool_id).unwrap();
+            pool.staked.0 += amount;
+            pool.total_deposited.0 += amount;
+            self.stake_then(
+                &pool_id,
+                amount,
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_STAKE)
+                    .on_sync_stake(pool_id.clone(), U128(amount)),
+            );
+        }
+        for (pool_id, withdrawn, drained) in unstakes {
+            let pool = self.find_staking_pool(&pool_id).unwrap();
+            pool.staked.0 = pool.staked.0.saturating_sub(withdrawn + drained);
+            pool.unstaked.0 += withdrawn;
+            pool.draining.0 += drained;
+            pool.unlock_epoch = epoch + UNSTAKE_DELAY_EPOCHS;
+            if pool.removed {
+                ext_staking_contract::ext(pool_id.clone())
+                    .with_static_gas(GAS_FOR_VIEW)
+                    .get_account_staked_balance(env::current_account_id())
+                    .then(
+                        Self::ext(env::current_account_id())
+                            .with_static_gas(GAS_FOR_ON_DRAIN_BALANCE)
+                            .on_drain_balance(
+                                pool_id.clone(),
+                                U128(withdrawn),
+                                U128(drained),
+                                closing.clone(),
+                            ),
+                    );
+                continue;
+            }
+            self.unstake_then(
+                &pool_id,
+                withdrawn + drained,
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_UNSTAKE)
+                    .on_sync_unstake(
+                        pool_id.clone(),
+                        U128(withdrawn),
+                        U128(drained),
+                        closing.clone(),
+                    ),
+            );
+        }
+    }
+
+    /// Keeps deposits a pool refused to stake pending for the next sync.
+    #[private]
+    pub fn on_sync_stake(&mut self, pool_id: AccountId, amount: U128) -> bool {
+        if is_promise_success() {
+            return true;
+        }
+        env::log_str(&format!(
+            "Staking {} in {} failed, keeping it pending",
+            amount.0, pool_id
+        ));
+        if let Some(pool) = self.find_staking_pool(&pool_id) {
+            pool.staked.0 -= amount.0;
+            pool.total_deposited.0 -= amount.0;
+        }
+        self.pending_stake += amount.0;
+        false
+    }
+
+    /// Unstakes everything a removed pool reports staked. What it holds
+    /// beyond the plan is drained too; if it holds less, the drain gives
+    /// way first and any withdrawals it cannot cover are retried at the
+    /// next sync like a refused unstake.
+    #[private]
+    pub fn on_drain_balance(
+        &mut self,
+        pool_id: AccountId,
+        withdrawn: U128,
+        drained: U128,
+        batches: Vec<u64>,
+        #[callback_result] staked: Result<U128, PromiseError>,
+    ) {
+        let staked = match staked {
+            Ok(staked) => staked.0,
+            Err(_) => {
+                self.return_unstake(&pool_id, withdrawn.0, drained.0, &batches);
+                return;
+            }
+        };
+        let short = (withdrawn.0 + drained.0).saturating_sub(staked);
+        let unwithdrawn = short - std::cmp::min(short, drained.0);
+        let withdrawn = withdrawn.0 - unwithdrawn;
+        let drained_now = staked - withdrawn;
+        let pool = self
+            .find_staking_pool(&pool_id)
+            .expect("Drained staking pool is missing");
+        pool.staked.0 = 0;
+        pool.unstaked.0 -= unwithdrawn;
+        pool.draining.0 = pool.draining.0 - drained.0 + drained_now;
+        if unwithdrawn > 0 {
+            self.unstake_shortfall += unwithdrawn;
+            self.reopen_batches(&batches);
+            self.last_sync_epoch = None;
+        }
+        if staked == 0 {
+            return;
+        }
+        self.unstake_then(
+            &pool_id,
+            staked,
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_ON_UNSTAKE)
+                .on_sync_unstake(pool_id.clone(), U128(withdrawn), U128(drained_now), batches),
+        );
+    }
+
+    /// Puts an unstake a pool refused back in its stake. The withdrawals it
+    /// was for are unstaked again at the next sync, which may run in this
+    /// same epoch, and `batches` wait for it; a drain simply resumes.
+    #[private]
+    pub fn on_sync_unstake(
+        &mut self,
+        pool_id: AccountId,
+        withdrawn: U128,
+        drained: U128,
+        batches: Vec<u64>,
+    ) -> bool {
+        if is_promise_success() {
+            return true;
+        }
+        env::log_str(&format!(
+            "Unstaking {} from {} failed, retrying at the next sync",
+            withdrawn.0 + drained.0,
+            pool_id
+        ));
+        self.return_unstake(&pool_id, withdrawn.0, drained.0, &batches);
+        false
+    }
+
+    pub fn get_stake_sync(&self) -> StakeSync {
+        StakeSync {
+            pending_stake: U128(self.pending_stake),
+            pending_unstake: U128(self.due_batches().1 + self.unstake_shortfall),
+            withdrawal_reserve: U128(self.withdrawal_reserve),
+            last_sync_epoch: self.last_sync_epoch,
+        }
+    }
+}
+
+impl Contract {
+    /// Puts an unstake that did not happen back in the pool's stake.
+    fn return_unstake(
+        &mut self,
+        pool_id: &AccountId,
+        withdrawn: Balance,
+        drained: Balance,
+        batches: &[u64],
+    ) {
+        if let Some(pool) = self.find_staking_pool(pool_id) {
+            pool.staked.0 += withdrawn + drained;
+            pool.unstaked.0 -= withdrawn;
+            pool.draining.0 -= drained;
+        }
+        if withdrawn > 0 {
+            self.unstake_shortfall += withdrawn;
+            self.reopen_batches(batches);
+        }
+        self.last_sync_epoch = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::{Ledger, PendingWithdrawal, UNSTAKE_DELAY_EPOCHS};
+    use near_sdk::test_utils::get_created_receipts;
+    use near_sdk::{Balance, PromiseResult};
+
+    fn invest_at(contract: &mut Contract, name: &str, amount: Balance, epoch: u64) {
+        set_context_at(name, amount, 0, epoch);
+        contract.invest(U128(amount));
+    }
+
+    fn withdraw_at(contract: &mut Contract, name: &str, amount: Balance, epoch: u64) {
+        set_context_at(name, 0, 0, epoch);
+        contract.withdraw(Ledger::Investor, U128(amount));
+    }
+
+    /// Alice has 10 NEAR staked since epoch 0.
+    fn setup_staked() -> Contract {
+        let mut contract = setup();
+        invest_at(&mut contract, "alice.testnet", 10 * MIN_DEPOSIT, 0);
+        contract.sync_stake();
+        contract
+    }
+
+    #[test]
+    fn test_deposits_wait_for_sync() {
+        let mut contract = setup();
+        invest_at(&mut contract, "alice.testnet", 5 * MIN_DEPOSIT, 0);
+        invest_at(&mut contract, "bob.testnet", 3 * MIN_DEPOSIT, 0);
+        assert!(function_calls().is_empty());
+        assert_eq!(
+            contract.get_stake_sync().pending_stake,
+            U128(8 * MIN_DEPOSIT)
+        );
+        // Nothing has reached the pool yet.
+        assert_eq!(contract.principal_in_pool(), U128(0));
+
+        contract.sync_stake();
+        assert_eq!(
+            function_calls()[0],
+            (
+                account("staking.testnet"),
+                "deposit_and_stake".to_string(),
+                8 * MIN_DEPOSIT
+            )
+        );
+        assert_eq!(contract.pending_stake, 0);
+        assert_eq!(contract.principal_in_pool(), U128(8 * MIN_DEPOSIT));
+    }
+
+    #[test]
+    fn test_deposits_cover_withdrawals_without_unstaking() {
+        let mut contract = setup_staked();
+        invest_at(&mut contract, "bob.testnet", 5 * MIN_DEPOSIT, 1);
+        withdraw_at(&mut contract, "alice.testnet", 2 * MIN_DEPOSIT, 1);
+        contract.sync_stake();
+
+        // Only the difference is staked.
+        let calls = function_calls();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].2, 3 * MIN_DEPOSIT);
+        assert_eq!(contract.withdrawal_reserve, 2 * MIN_DEPOSIT);
+        assert_eq!(
+            contract.get_pending_withdrawals(account("alice.testnet")),
+            vec![PendingWithdrawal {
+                amount: U128(2 * MIN_DEPOSIT),
+                available_epoch: 1,
+                unstaked: true,
+            }]
+        );
+        assert_eq!(contract.principal_in_pool(), U128(13 * MIN_DEPOSIT));
+
+        set_context_at("alice.testnet", 0, 0, 1);
+        contract.complete_withdrawal();
+        assert_eq!(contract.withdrawal_reserve, 0);
+        assert_eq!(contract.total_unstaking, 0);
+        let receipts = get_created_receipts();
+        assert_eq!(receipts[0].receiver_id, account("alice.testnet"));
+    }
+
+    #[test]
+    fn test_withdrawals_beyond_deposits_are_unstaked() {
+        let mut contract = setup_staked();
+        invest_at(&mut contract, "bob.testnet", MIN_DEPOSIT, 1);
+        withdraw_at(&mut contract, "alice.testnet", 4 * MIN_DEPOSIT, 1);
+        contract.sync_stake();
+
+        let calls = function_calls();
+        assert_eq!(calls[0].1, "unstake");
+        assert_eq!(calls[1].1, "on_sync_unstake");
+        assert_eq!(contract.withdrawal_reserve, MIN_DEPOSIT);
+        assert_eq!(
+            contract.get_pending_withdrawals(account("alice.testnet"))[0].available_epoch,
+            1 + UNSTAKE_DELAY_EPOCHS
+        );
+
+        // The reserve pays first and the pool the rest.
+        set_context_at("alice.testnet", 0, 0, 1 + UNSTAKE_DELAY_EPOCHS);
+        contract.complete_withdrawal();
+        let receipts = get_created_receipts();
+        assert_eq!(receipts[0].receiver_id, account("alice.testnet"));
+        assert_eq!(receipts[2].receiver_id, account("staking.testnet"));
+        assert_eq!(contract.withdrawal_reserve, 0);
+        assert_eq!(contract.total_unstaking, 3 * MIN_DEPOSIT);
+    }
+
+    #[test]
+    fn test_failed_stake_stays_pending() {
+        let mut contract = setup();
+        invest_at(&mut contract, "alice.testnet", 5 * MIN_DEPOSIT, 0);
+        contract.sync_stake();
+
+        set_callback_context(vec![PromiseResult::Failed]);
+        assert!(!contract.on_sync_stake(account("staking.testnet"), U128(5 * MIN_DEPOSIT)));
+        assert_eq!(contract.pending_stake, 5 * MIN_DEPOSIT);
+        assert_eq!(contract.total_stake, 5 * MIN_DEPOSIT);
+    }
+
+    #[test]
+    fn test_failed_unstake_is_retried_at_the_next_sync() {
+        let mut contract = setup_staked();
+        invest_at(&mut contract, "bob.testnet", MIN_DEPOSIT, 1);
+        withdraw_at(&mut contract, "alice.testnet", 4 * MIN_DEPOSIT, 1);
+        contract.sync_stake();
+
+        set_callback_context(vec![PromiseResult::Failed]);
+        assert!(!contract.on_sync_unstake(
+            account("staking.testnet"),
+            U128(3 * MIN_DEPOSIT),
+            U128(0),
+            vec![1]
+        ));
+        assert_eq!(contract.staking_pools[0].staked, U128(10 * MIN_DEPOSIT));
+        set_context_at("keeper.testnet", 0, 0, 1);
+        assert_eq!(
+            contract.get_stake_sync(),
+            StakeSync {
+                pending_stake: U128(0),
+                pending_unstake: U128(3 * MIN_DEPOSIT),
+                withdrawal_reserve: U128(MIN_DEPOSIT),
+                last_sync_epoch: None,
+            }
+        );
+
+        // The retry can run in the same epoch.
+        contract.sync_stake();
+        let calls = function_calls();
+        assert_eq!(calls[0].1, "unstake");
+        assert_eq!(contract.unstake_shortfall, 0);
+        assert_eq!(contract.staking_pools[0].unstaked, U128(3 * MIN_DEPOSIT));
+    }
+
+    #[test]
+    fn test_unstake_waits_for_the_pool_to_be_withdrawn_from() {
+        let mut contract = setup_staked();
+        withdraw_at(&mut contract, "alice.testnet", 3 * MIN_DEPOSIT, 1);
+        contract.sync_stake();
+        withdraw_at(&mut contract, "alice.testnet", 2 * MIN_DEPOSIT, 2);
+        contract.sync_stake();
+
+        // Unstaking now would lock the first batch again until epoch 6.
+        assert!(function_calls().is_empty());
+        assert_eq!(contract.get_unstake_queue(), vec![2]);
+        assert_eq!(
+            contract.get_pending_withdrawals(account("alice.testnet")),
+            vec![
+                PendingWithdrawal {
+                    amount: U128(3 * MIN_DEPOSIT),
+                    available_epoch: 1 + UNSTAKE_DELAY_EPOCHS,
+                    unstaked: true,
+                },
+                PendingWithdrawal {
+                    amount: U128(2 * MIN_DEPOSIT),
+                    available_epoch: 2 + UNSTAKE_DELAY_EPOCHS,
+                    unstaked: false,
+                },
+            ]
+        );
+
+        set_context_at("alice.testnet", 0, 0, 1 + UNSTAKE_DELAY_EPOCHS);
+        contract.complete_withdrawal();
+        set_callback_context(vec![PromiseResult::Successful(vec![])]);
+        contract.on_pool_withdraw(
+            account("alice.testnet"),
+            Ledger::Investor,
+            account("staking.testnet"),
+            U128(3 * MIN_DEPOSIT),
+        );
+        set_context_at("keeper.testnet", 0, 0, 1 + UNSTAKE_DELAY_EPOCHS);
+        contract.sync_stake();
+        assert_eq!(function_calls()[0].1, "unstake");
+        assert!(contract.get_unstake_queue().is_empty());
+        assert_eq!(
+            contract.staking_pools[0].unlock_epoch,
+            1 + 2 * UNSTAKE_DELAY_EPOCHS
+        );
+        assert_eq!(
+            contract.get_pending_withdrawals(account("alice.testnet"))[0].available_epoch,
+            1 + 2 * UNSTAKE_DELAY_EPOCHS
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Nothing to stake or unstake")]
+    fn test_sync_needs_something_to_do() {
+        let mut contract = setup();
+        set_context("keeper.testnet", 0);
+        contract.sync_stake();
+    }
+}
@@ -0,0 +1,138 @@
+//! Shared fixtures for the unit tests of every contract module.
+use near_sdk::json_types::U128;
+use near_sdk::mock::VmAction;
+use near_sdk::serde_json::{self, Value};
+use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+use near_sdk::{
+    testing_env, AccountId, Balance, PromiseOrValue, PromiseResult, RuntimeFeesConfig, VMConfig,
+};
+
+use crate::{Contract, Round};
+
+pub const MIN_DEPOSIT: Balance = 1_000_000;
+pub const INITIAL_BALANCE: Balance = 10u128.pow(24);
+pub const CONTRACT_ID: &str = "lottery.testnet";
+
+pub fn account(name: &str) -> AccountId {
+    name.parse().unwrap()
+}
+
+pub fn set_context(predecessor: &str, deposit: Balance) {
+    set_context_at(predecessor, deposit, 0, 0);
+}
+
+pub fn set_context_at(predecessor: &str, deposit: Balance, timestamp: u64, epoch: u64) {
+    let context = VMContextBuilder::new()
+        .current_account_id(account(CONTRACT_ID))
+        .predecessor_account_id(account(predecessor))
+        .account_balance(INITIAL_BALANCE)
+        .attached_deposit(deposit)
+        .block_timestamp(timestamp)
+        .epoch_height(epoch)
+        .build();
+    testing_env!(context);
+}
+
+/// Sets up a context at a given block, as the reveal step of a draw needs.
+pub fn set_block_context(
+    predecessor: &str,
+    timestamp: u64,
+    block_height: u64,
+    random_seed: [u8; 32],
+) {
+    let context = VMContextBuilder::new()
+        .current_account_id(account(CONTRACT_ID))
+        .predecessor_account_id(account(predecessor))
+        .account_balance(INITIAL_BALANCE)
+        .block_timestamp(timestamp)
+        .block_index(block_height)
+        .random_seed(random_seed)
+        .build();
+    testing_env!(context);
+}
+
+/// Sets up a context in which the contract is calling itself back with the
+/// given promise results, as `#[private]` callbacks expect.
+pub fn set_callback_context(results: Vec<PromiseResult>) {
+    let context = VMContextBuilder::new()
+        .current_account_id(account(CONTRACT_ID))
+        .predecessor_account_id(account(CONTRACT_ID))
+        .account_balance(INITIAL_BALANCE)
+        .build();
+    testing_env!(
+        context,
+        VMConfig::test(),
+        RuntimeFeesConfig::test(),
+        Default::default(),
+        results
+    );
+}
+
+/// A staking pool's answer to a balance view.
+pub fn pool_result(total: Balance) -> Vec<PromiseResult> {
+    vec![PromiseResult::Successful(
+        format!("\"{}\"", total).into_bytes(),
+    )]
+}
+
+/// Completes a committed draw: the pool reports `total`, and the winner is
+/// revealed one block later.
+pub fn settle(contract: &mut Contract, total: Balance, timestamp: u64) -> Round {
+    set_callback_context(pool_result(total));
+    contract.on_draw_yield(vec![account("staking.testnet")]);
+    set_block_context("keeper.testnet", timestamp, 1, [7; 32]);
+    match contract.draw() {
+        PromiseOrValue::Value(round) => round,
+        PromiseOrValue::Promise(_) => panic!("Expected the reveal step"),
+    }
+}
+
+/// Deploys a fresh contract on empty storage.
+pub fn setup() -> Contract {
+    near_sdk::mock::with_mocked_blockchain(|blockchain| {
+        blockchain.take_storage();
+    });
+    set_context("owner.testnet", 0);
+    Contract::new(
+        account("owner.testnet"),
+        account("staking.testnet"),
+        U128(MIN_DEPOSIT),
+    )
+}
+
+/// Every function call scheduled so far, as `(receiver, method, deposit)`.
+pub fn function_calls() -> Vec<(AccountId, String, Balance)> {
+    get_created_receipts()
+        .into_iter()
+        .flat_map(|receipt| {
+            let receiver_id = receipt.receiver_id.clone();
+            receipt
+                .actions
+                .into_iter()
+                .filter_map(move |action| match action {
+                    VmAction::FunctionCall {
+                        function_name,
+                        deposit,
+                        ..
+                    } => Some((receiver_id.clone(), function_name, deposit)),
+                    _ => None,
+                })
+        })
+        .collect()
+}
+
+/// The JSON arguments of the first scheduled call to `method`.
+pub fn function_call_args(method: &str) -> Value {
+    get_created_receipts()
+        .into_iter()
+        .flat_map(|receipt| receipt.actions)
+        .find_map(|action| match action {
+            VmAction::FunctionCall {
+                function_name,
+                args,
+                ..
+            } if function_name == method => Some(serde_json::from_slice(&args).unwrap()),
+            _ => None,
+        })
+        .unwrap_or_else(|| panic!("No call to {}", method))
+}
@@ -0,0 +1,359 @@
+//! Deposits made in NEP-141 tokens such as wNEAR or liquid staking tokens.
+//! The owner allowlists the tokens the contract takes; a transfer's `msg`
+//! says whether it is an investment or a play. Token deposits are held as
+//! they are and paid back in the same token, never converted to NEAR.
+//!
+//! Tokens are only held in custody, per token and ledger. They are not
+//! staked, so they earn no yield: an investment in tokens mints no shares
+//! and a play in tokens buys no tickets, leaving the odds of the NEAR staked
+//! by players as they are. Both are taken out with `withdraw_token`.
+use near_contract_standards::fungible_token::core::ext_ft_core;
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
+use near_sdk::{env, near_bindgen, AccountId, Balance, Gas, Promise, PromiseOrValue};
+
+use crate::events;
+use crate::{is_promise_success, Contract, ContractExt, Ledger};
+
+const GAS_FOR_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_ON_TOKEN_WITHDRAW: Gas = Gas(10_000_000_000_000);
+
+/// What the contract holds of one token, per ledger.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenTotals {
+    pub invested: U128,
+    pub played: U128,
+}
+
+impl Default for TokenTotals {
+    fn default() -> Self {
+        Self {
+            invested: U128(0),
+            played: U128(0),
+        }
+    }
+}
+
+impl TokenTotals {
+    fn get_mut(&mut self, ledger: Ledger) -> &mut U128 {
+        match ledger {
+            Ledger::Investor => &mut self.invested,
+            Ledger::Player => &mut self.played,
+        }
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for Contract {
+    /// Takes a deposit of an allowlisted token; `msg` is either "invest" or
+    /// "play". Anything else panics, so the token refunds the sender.
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let token_id = env::predecessor_account_id();
+        assert!(
+            self.accepted_tokens.contains(&token_id),
+            "Token {} is not accepted",
+            token_id
+        );
+        let ledger = match msg.as_str() {
+            "invest" => Ledger::Investor,
+            "play" => Ledger::Player,
+            _ => panic!("Expected \"invest\" or \"play\", got \"{}\"", msg),
+        };
+        assert!(amount.0 > 0, "Deposit must be greater than zero");
+
+        self.credit_token(&token_id, ledger, &sender_id, amount.0);
+        self.record_deposit(&sender_id, ledger, amount.0, Some(token_id.clone()));
+        events::emit(
+            "token_deposit",
+            json!({
+                "token_id": token_id,
+                "account_id": sender_id,
+                "ledger": ledger,
+                "amount": amount,
+            }),
+        );
+        PromiseOrValue::Value(U128(0))
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn add_accepted_token(&mut self, token_id: AccountId) {
+        self.assert_owner();
+        self.accepted_tokens.insert(&token_id);
+    }
+
+    /// Stops taking new deposits of the token. What is already deposited
+    /// can still be withdrawn.
+    pub fn remove_accepted_token(&mut self, token_id: AccountId) {
+        self.assert_owner();
+        self.accepted_tokens.remove(&token_id);
+    }
+
+    pub fn get_accepted_tokens(&self) -> Vec<AccountId> {
+        self.accepted_tokens.to_vec()
+    }
+
+    pub fn get_token_totals(&self, token_id: AccountId) -> TokenTotals {
+        self.token_totals.get(&token_id).unwrap_or_default()
+    }
+
+    pub fn get_token_balance(
+        &self,
+        token_id: AccountId,
+        ledger: Ledger,
+        account_id: AccountId,
+    ) -> U128 {
+        U128(self.token_balance(&token_id, ledger, &account_id))
+    }
+
+    /// Sends `amount` of the caller's `token_id` deposit in `ledger` back
+    /// to them, restoring it if the token refuses the transfer.
+    pub fn withdraw_token(&mut self, token_id: AccountId, ledger: Ledger, amount: U128) -> Promise {
+        let account_id = env::predecessor_account_id();
+        assert!(amount.0 > 0, "Withdrawal amount must be greater than zero");
+        self.debit_token(&token_id, ledger, &account_id, amount.0);
+
+        ext_ft_core::ext(token_id.clone())
+            .with_attached_deposit(1)
+            .with_static_gas(GAS_FOR_FT_TRANSFER)
+            .ft_transfer(account_id.clone(), amount, None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_TOKEN_WITHDRAW)
+                    .on_token_withdraw(token_id, ledger, account_id, amount),
+            )
+    }
+
+    /// Restores a token withdrawal the token refused.
+    #[private]
+    pub fn on_token_withdraw(
+        &mut self,
+        token_id: AccountId,
+        ledger: Ledger,
+        account_id: AccountId,
+        amount: U128,
+    ) -> bool {
+        if is_promise_success() {
+            return true;
+        }
+        env::log_str(&format!(
+            "Transfer of {} {} to {} failed, restoring balance",
+            amount.0, token_id, account_id
+        ));
+        self.credit_token(&token_id, ledger, &account_id, amount.0);
+        false
+    }
+}
+
+impl Contract {
+    pub(crate) fn token_balance(
+        &self,
+        token_id: &AccountId,
+        ledger: Ledger,
+        account_id: &AccountId,
+    ) -> Balance {
+        self.token_balances
+            .get(&(token_id.clone(), ledger, account_id.clone()))
+            .unwrap_or(0)
+    }
+
+    fn credit_token(
+        &mut self,
+        token_id: &AccountId,
+        ledger: Ledger,
+        account_id: &AccountId,
+        amount: Balance,
+    ) {
+        let balance = self.token_balance(token_id, ledger, account_id) + amount;
+        self.token_balances
+            .insert(&(token_id.clone(), ledger, account_id.clone()), &balance);
+        let mut totals = self.get_token_totals(token_id.clone());
+        totals.get_mut(ledger).0 += amount;
+        self.token_totals.insert(token_id, &totals);
+    }
+
+    fn debit_token(
+        &mut self,
+        token_id: &AccountId,
+        ledger: Ledger,
+        account_id: &AccountId,
+        amount: Balance,
+    ) {
+        let balance = self.token_balance(token_id, ledger, account_id);
+        assert!(amount <= balance, "Withdrawal exceeds balance");
+        let key = (token_id.clone(), ledger, account_id.clone());
+        if balance == amount {
+            self.token_balances.remove(&key);
+        } else {
+            self.token_balances.insert(&key, &(balance - amount));
+        }
+        let mut totals = self.get_token_totals(token_id.clone());
+        totals.get_mut(ledger).0 -= amount;
+        self.token_totals.insert(token_id, &totals);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    const WNEAR: &str = "wrap.testnet";
+    const USDC: &str = "usdc.testnet";
+
+    /// Accepts wNEAR and USDC.
+    fn setup_tokens() -> Contract {
+        let mut contract = setup();
+        set_context("owner.testnet", 0);
+        contract.add_accepted_token(account(WNEAR));
+        contract.add_accepted_token(account(USDC));
+        contract
+    }
+
+    /// `name` sends `amount` of `token` to the contract with `msg`.
+    fn transfer_in(contract: &mut Contract, token: &str, name: &str, amount: Balance, msg: &str) {
+        set_context(token, 0);
+        match contract.ft_on_transfer(account(name), U128(amount), msg.to_string()) {
+            PromiseOrValue::Value(unused) => assert_eq!(unused, U128(0)),
+            PromiseOrValue::Promise(_) => panic!("Expected the deposit to be kept"),
+        }
+    }
+
+    #[test]
+    fn test_token_play_buys_no_tickets() {
+        let mut contract = setup_tokens();
+        set_context("alice.testnet", 2 * MIN_DEPOSIT);
+        contract.play(U128(2 * MIN_DEPOSIT));
+        let weight = contract.total_twab_weight();
+        transfer_in(&mut contract, WNEAR, "bob.testnet", 3 * MIN_DEPOSIT, "play");
+
+        assert_eq!(contract.total_player_deposit(), 2 * MIN_DEPOSIT);
+        assert_eq!(contract.total_twab_weight(), weight);
+        assert_eq!(contract.players.get(&account("bob.testnet")), None);
+        assert_eq!(
+            contract.get_token_balance(account(WNEAR), Ledger::Player, account("bob.testnet")),
+            U128(3 * MIN_DEPOSIT)
+        );
+        assert_eq!(
+            contract.get_token_totals(account(WNEAR)).played,
+            U128(3 * MIN_DEPOSIT)
+        );
+        let history = contract.get_deposit_history(account("bob.testnet"), None, None);
+        assert_eq!(history[0].token_id, Some(account(WNEAR)));
+        assert_eq!(history[0].amount, U128(3 * MIN_DEPOSIT));
+    }
+
+    #[test]
+    fn test_token_invest_is_held_per_token() {
+        let mut contract = setup_tokens();
+        transfer_in(
+            &mut contract,
+            WNEAR,
+            "alice.testnet",
+            5 * MIN_DEPOSIT,
+            "invest",
+        );
+
+        assert_eq!(
+            contract.get_token_totals(account(WNEAR)),
+            TokenTotals {
+                invested: U128(5 * MIN_DEPOSIT),
+                played: U128(0),
+            }
+        );
+        assert_eq!(contract.get_total_shares(), U128(0));
+        let history = contract.get_deposit_history(account("alice.testnet"), None, None);
+        assert_eq!(history[0].token_id, Some(account(WNEAR)));
+        assert_eq!(history[0].amount, U128(5 * MIN_DEPOSIT));
+    }
+
+    #[test]
+    #[should_panic(expected = "Token stnear.testnet is not accepted")]
+    fn test_rejects_unlisted_token() {
+        let mut contract = setup_tokens();
+        transfer_in(
+            &mut contract,
+            "stnear.testnet",
+            "bob.testnet",
+            MIN_DEPOSIT,
+            "play",
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected \"invest\" or \"play\", got \"stake\"")]
+    fn test_rejects_unknown_msg() {
+        let mut contract = setup_tokens();
+        transfer_in(&mut contract, WNEAR, "bob.testnet", MIN_DEPOSIT, "stake");
+    }
+
+    #[test]
+    #[should_panic(expected = "Token wrap.testnet is not accepted")]
+    fn test_removed_token_takes_no_deposits() {
+        let mut contract = setup_tokens();
+        set_context("owner.testnet", 0);
+        contract.remove_accepted_token(account(WNEAR));
+        assert_eq!(contract.get_accepted_tokens(), vec![account(USDC)]);
+        transfer_in(&mut contract, WNEAR, "bob.testnet", MIN_DEPOSIT, "play");
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_allowlist_is_owner_only() {
+        let mut contract = setup();
+        set_context("bob.testnet", 0);
+        contract.add_accepted_token(account(WNEAR));
+    }
+
+    #[test]
+    fn test_withdraw_token_sends_it_back() {
+        let mut contract = setup_tokens();
+        transfer_in(&mut contract, WNEAR, "bob.testnet", 2 * MIN_DEPOSIT, "play");
+        set_context("bob.testnet", 0);
+        contract.withdraw_token(account(WNEAR), Ledger::Player, U128(2 * MIN_DEPOSIT));
+
+        assert_eq!(contract.get_token_totals(account(WNEAR)).played, U128(0));
+        assert!(function_calls().contains(&(account(WNEAR), "ft_transfer".to_string(), 1)));
+    }
+
+    #[test]
+    fn test_failed_token_withdrawal_is_restored() {
+        let mut contract = setup_tokens();
+        transfer_in(&mut contract, WNEAR, "bob.testnet", 2 * MIN_DEPOSIT, "play");
+        set_context("bob.testnet", 0);
+        contract.withdraw_token(account(WNEAR), Ledger::Player, U128(2 * MIN_DEPOSIT));
+
+        set_callback_context(vec![near_sdk::PromiseResult::Failed]);
+        assert!(!contract.on_token_withdraw(
+            account(WNEAR),
+            Ledger::Player,
+            account("bob.testnet"),
+            U128(2 * MIN_DEPOSIT),
+        ));
+        assert_eq!(
+            contract.get_token_balance(account(WNEAR), Ledger::Player, account("bob.testnet")),
+            U128(2 * MIN_DEPOSIT)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Withdrawal exceeds balance")]
+    fn test_token_play_is_not_paid_out_in_near() {
+        let mut contract = setup_tokens();
+        set_context("bob.testnet", MIN_DEPOSIT);
+        contract.play(U128(MIN_DEPOSIT));
+        transfer_in(&mut contract, WNEAR, "bob.testnet", MIN_DEPOSIT, "play");
+        set_context("bob.testnet", 0);
+        contract.withdraw(Ledger::Player, U128(2 * MIN_DEPOSIT));
+    }
+}
@@ -0,0 +1,302 @@
+//! Time-weighted balances. A player's odds in a round are the integral of
+//! their ticket count over the round, so a deposit made just before the draw
+//! is worth only the seconds it was actually held. Weights are mirrored into
+//! a `FenwickTree` so the draw never has to walk the player list.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde_json;
+use near_sdk::{env, near_bindgen, AccountId, Balance};
+
+use crate::events;
+use crate::receipt::{hash_twab_update, TwabUpdate};
+use crate::selection::tickets_for;
+use crate::{Contract, ContractExt, Round};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+/// A player's ticket-seconds for `round_id`, projected to the round's end
+/// on the assumption that their balance does not change again.
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, PartialEq)]
+pub struct Twab {
+    pub round_id: u64,
+    pub weight: u128,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Time-weighted average balance of `account_id` over the current round,
+    /// counting only whole tickets.
+    pub fn get_twab(&self, account_id: AccountId) -> U128 {
+        let round = self.current_round();
+        let weight = self.twab_weight(&account_id, &round);
+        U128(weight / round_seconds(&round).max(1) as u128 * self.min_deposit)
+    }
+}
+
+impl Contract {
+    /// The player's weight in `round`. Players not touched since the round
+    /// opened have held their current balance for all of it.
+    pub(crate) fn twab_weight(&self, account_id: &AccountId, round: &Round) -> u128 {
+        let balance = self.players.get(account_id).unwrap_or(0);
+        self.twab_weight_with_balance(account_id, round, balance)
+    }
+
+    /// Moves the player's projected weight by the change in tickets times
+    /// the time left in the round.
+    pub(crate) fn update_twab(&mut self, account_id: &AccountId, old: Balance, new: Balance) {
+        let round = self.current_round();
+        let old_weight = self.twab_weight_with_balance(account_id, &round, old);
+        let remaining = seconds_left(&round, env::block_timestamp()) as u128;
+        let old_tickets = tickets_for(old, self.min_deposit);
+        let new_tickets = tickets_for(new, self.min_deposit);
+        let weight = if new_tickets >= old_tickets {
+            old_weight + (new_tickets - old_tickets) * remaining
+        } else {
+            old_weight.saturating_sub((old_tickets - new_tickets) * remaining)
+        };
+        self.set_twab(
+            account_id,
+            &round,
+            (old_tickets, old_weight),
+            (new_tickets, weight),
+        );
+    }
+
+    fn twab_weight_with_balance(
+        &self,
+        account_id: &AccountId,
+        round: &Round,
+        balance: Balance,
+    ) -> u128 {
+        match self.twabs.get(account_id) {
+            Some(twab) if twab.round_id == round.id => twab.weight,
+            _ => tickets_for(balance, self.min_deposit) * round_seconds(round) as u128,
+        }
+    }
+
+    /// Stores the player's new weight and applies the change to their slot
+    /// in the tree, handing out a slot on their first deposit. The change is
+    /// logged as a `twab_update` event and folded into `twab_hash`, so a
+    /// draw receipt commits to the events `receipt::participants` rebuilds
+    /// the round's weights from.
+    fn set_twab(
+        &mut self,
+        account_id: &AccountId,
+        round: &Round,
+        (old_tickets, old_weight): (u128, u128),
+        (new_tickets, new_weight): (u128, u128),
+    ) {
+        self.twabs.insert(
+            account_id,
+            &Twab {
+                round_id: round.id,
+                weight: new_weight,
+            },
+        );
+        let slot = match self.player_slots.get(account_id) {
+            Some(slot) => slot,
+            None => {
+                let slot = self.twab_tree.push();
+                self.player_slots.insert(account_id, &slot);
+                self.slot_owners.push(account_id);
+                slot
+            }
+        };
+        let tickets = new_tickets as i128 - old_tickets as i128;
+        let weight = new_weight as i128 - old_weight as i128;
+        let deficit = tickets * round_seconds(round) as i128 - weight;
+        self.twab_tree.update(slot, round.id, tickets, deficit);
+        let update = TwabUpdate {
+            account_id: account_id.clone(),
+            slot,
+            round_id: round.id,
+            tickets: U128(new_tickets),
+            weight: U128(new_weight),
+        };
+        self.twab_hash = hash_twab_update(&self.twab_hash, &update);
+        self.twab_updates += 1;
+        events::emit("twab_update", serde_json::to_value(&update).unwrap());
+    }
+}
+
+pub(crate) fn round_seconds(round: &Round) -> u64 {
+    (round.end_timestamp.0 - round.start_timestamp.0) / NANOS_PER_SECOND
+}
+
+/// Whole seconds between `timestamp` and the round's end, clamped to the
+/// round itself.
+fn seconds_left(round: &Round, timestamp: u64) -> u64 {
+    let clamped = timestamp.clamp(round.start_timestamp.0, round.end_timestamp.0);
+    (round.end_timestamp.0 - clamped) / NANOS_PER_SECOND
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::round::DEFAULT_ROUND_DURATION;
+    use crate::test_utils::*;
+    use crate::Ledger;
+
+    const ROUND_SECONDS: u128 = (DEFAULT_ROUND_DURATION / NANOS_PER_SECOND) as u128;
+
+    fn play_at(contract: &mut Contract, name: &str, tickets: u128, timestamp: u64) {
+        set_context_at(name, tickets * MIN_DEPOSIT, timestamp, 0);
+        contract.play(U128(tickets * MIN_DEPOSIT));
+    }
+
+    fn weight(contract: &Contract, name: &str) -> u128 {
+        contract.twab_weight(&account(name), &contract.current_round())
+    }
+
+    #[test]
+    fn test_deposit_held_all_round_counts_fully() {
+        let mut contract = setup();
+        play_at(&mut contract, "bob.testnet", 3, 0);
+        assert_eq!(weight(&contract, "bob.testnet"), 3 * ROUND_SECONDS);
+        assert_eq!(
+            contract.get_twab(account("bob.testnet")),
+            U128(3 * MIN_DEPOSIT)
+        );
+    }
+
+    #[test]
+    fn test_last_second_deposit_barely_counts() {
+        let mut contract = setup();
+        play_at(&mut contract, "early.testnet", 1, 0);
+        play_at(
+            &mut contract,
+            "late.testnet",
+            100,
+            DEFAULT_ROUND_DURATION - NANOS_PER_SECOND,
+        );
+        assert_eq!(weight(&contract, "early.testnet"), ROUND_SECONDS);
+        assert_eq!(weight(&contract, "late.testnet"), 100);
+        assert_eq!(contract.get_twab(account("late.testnet")), U128(0));
+    }
+
+    #[test]
+    fn test_withdrawal_mid_round_reduces_weight() {
+        let mut contract = setup();
+        play_at(&mut contract, "bob.testnet", 4, 0);
+        set_context_at("bob.testnet", 0, DEFAULT_ROUND_DURATION / 2, 0);
+        contract.withdraw(Ledger::Player, U128(2 * MIN_DEPOSIT));
+        assert_eq!(
+            weight(&contract, "bob.testnet"),
+            4 * ROUND_SECONDS / 2 + 2 * ROUND_SECONDS / 2
+        );
+    }
+
+    #[test]
+    fn test_deposit_after_round_end_does_not_count_for_it() {
+        let mut contract = setup();
+        play_at(&mut contract, "bob.testnet", 1, 0);
+        play_at(&mut contract, "bob.testnet", 50, DEFAULT_ROUND_DURATION + 1);
+        assert_eq!(weight(&contract, "bob.testnet"), ROUND_SECONDS);
+    }
+
+    #[test]
+    fn test_draw_weights_by_twab_not_spot_balance() {
+        let mut contract = setup();
+        set_context("alice.testnet", 100 * MIN_DEPOSIT);
+        contract.invest(U128(100 * MIN_DEPOSIT));
+        play_at(&mut contract, "early.testnet", 1, 0);
+        // A whale arriving one second before the end holds far fewer
+        // ticket-seconds than the player who was there all week.
+        play_at(
+            &mut contract,
+            "whale.testnet",
+            1000,
+            DEFAULT_ROUND_DURATION - NANOS_PER_SECOND,
+        );
+        assert_eq!(contract.total_twab_weight(), ROUND_SECONDS + 1000);
+    }
+
+    #[test]
+    fn test_weight_resets_for_next_round() {
+        let mut contract = setup();
+        set_context("alice.testnet", 100 * MIN_DEPOSIT);
+        contract.invest(U128(100 * MIN_DEPOSIT));
+        play_at(&mut contract, "bob.testnet", 2, DEFAULT_ROUND_DURATION / 2);
+        assert_eq!(weight(&contract, "bob.testnet"), ROUND_SECONDS);
+
+        set_context_at("keeper.testnet", 0, DEFAULT_ROUND_DURATION, 0);
+        contract.draw();
+        settle(&mut contract, 100 * MIN_DEPOSIT, DEFAULT_ROUND_DURATION);
+
+        assert_eq!(contract.get_current_round().id, 1);
+        assert_eq!(weight(&contract, "bob.testnet"), 2 * ROUND_SECONDS);
+    }
+
+    #[test]
+    fn test_tree_tracks_player_weights() {
+        let mut contract = setup();
+        play_at(&mut contract, "a.testnet", 2, 0);
+        play_at(&mut contract, "b.testnet", 3, DEFAULT_ROUND_DURATION / 2);
+        set_context_at("a.testnet", 0, DEFAULT_ROUND_DURATION / 4, 0);
+        contract.withdraw(Ledger::Player, U128(MIN_DEPOSIT));
+
+        let expected = weight(&contract, "a.testnet") + weight(&contract, "b.testnet");
+        assert_eq!(contract.total_twab_weight(), expected);
+
+        set_context_at("a.testnet", 0, DEFAULT_ROUND_DURATION / 2, 0);
+        contract.withdraw_all(Ledger::Player);
+        let expected = weight(&contract, "a.testnet") + weight(&contract, "b.testnet");
+        assert_eq!(contract.total_twab_weight(), expected);
+    }
+
+    #[test]
+    fn test_partial_and_full_exit_keep_the_weight_held() {
+        let mut contract = setup();
+        play_at(&mut contract, "a.testnet", 4, 0);
+        play_at(&mut contract, "b.testnet", 4, 0);
+        set_context_at("a.testnet", 0, DEFAULT_ROUND_DURATION / 2, 0);
+        contract.withdraw(Ledger::Player, U128(3 * MIN_DEPOSIT));
+        set_context_at("b.testnet", 0, DEFAULT_ROUND_DURATION / 2, 0);
+        contract.withdraw_all(Ledger::Player);
+
+        // Keeping one ticket is worth that ticket's time and nothing more.
+        assert_eq!(weight(&contract, "b.testnet"), 4 * ROUND_SECONDS / 2);
+        assert_eq!(
+            weight(&contract, "a.testnet"),
+            weight(&contract, "b.testnet") + ROUND_SECONDS / 2
+        );
+    }
+
+    #[test]
+    fn test_weight_frozen_after_round_end() {
+        let mut contract = setup();
+        play_at(&mut contract, "a.testnet", 2, 0);
+        play_at(&mut contract, "b.testnet", 3, 0);
+        set_context_at("a.testnet", 0, DEFAULT_ROUND_DURATION, 0);
+        contract.withdraw_all(Ledger::Player);
+
+        assert_eq!(weight(&contract, "a.testnet"), 2 * ROUND_SECONDS);
+        assert_eq!(contract.total_twab_weight(), 5 * ROUND_SECONDS);
+    }
+
+    /// Fills the tree with `players` single-ticket players and returns the
+    /// gas a winner lookup burns.
+    fn draw_gas_with(players: u64) -> u64 {
+        let mut contract = setup();
+        for i in 0..players {
+            if i % 10 == 0 {
+                set_context("keeper.testnet", 0);
+            }
+            let player = account(&format!("player{}.testnet", i));
+            contract.credit(&player, Ledger::Player, MIN_DEPOSIT);
+        }
+        set_context("keeper.testnet", 0);
+        let before = env::used_gas();
+        contract.select_winners(&[0; 32], 1);
+        (env::used_gas() - before).0
+    }
+
+    #[test]
+    fn test_draw_gas_grows_logarithmically() {
+        let small = draw_gas_with(1_000);
+        let large = draw_gas_with(100_000);
+        // log2(100k) / log2(1k) is about 1.7; a linear scan would be 100x.
+        assert!(large < small * 2, "{} vs {}", large, small);
+        assert!(large < 10_000_000_000_000, "draw used {} gas", large);
+    }
+}
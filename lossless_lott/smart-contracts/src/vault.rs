@@ -0,0 +1,297 @@
+//! Investor positions as vault shares. Investors own `vault_assets`, which
+//! grows with their cut of every round's yield, and each share is a
+//! pro-rata claim on it, so someone joining later buys in at the current
+//! price instead of diluting earlier investors. As in ERC-4626, every
+//! conversion rounds in the vault's favour, and one virtual share and asset
+//! keep the price from being skewed while the vault is empty.
+use near_contract_standards::fungible_token::events::{FtBurn, FtMint};
+use near_sdk::json_types::U128;
+use near_sdk::{env, near_bindgen, AccountId, Balance};
+
+use crate::{Contract, ContractExt, Ledger};
+use u256::U256;
+
+#[allow(clippy::assign_op_pattern, clippy::manual_div_ceil)]
+mod u256 {
+    uint::construct_uint! {
+        /// 256-bit unsigned integer, wide enough for `assets * shares`.
+        pub struct U256(4);
+    }
+}
+
+/// `a * b / c`, rounded up if `round_up` is set.
+pub(crate) fn mul_div(a: u128, b: u128, c: u128, round_up: bool) -> u128 {
+    let (a, b, c) = (U256::from(a), U256::from(b), U256::from(c));
+    let mut result = a * b / c;
+    if round_up && result * c != a * b {
+        result += U256::one();
+    }
+    result.as_u128()
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Burns `shares` of the caller's position and unstakes what they are
+    /// worth, which becomes claimable through `complete_withdrawal`.
+    pub fn redeem(&mut self, shares: U128) -> U128 {
+        let account_id = env::predecessor_account_id();
+        assert!(shares.0 > 0, "Withdrawal amount must be greater than zero");
+        U128(self.redeem_shares(&account_id, shares.0))
+    }
+
+    /// Everything investors own, principal and accrued yield.
+    pub fn total_assets(&self) -> U128 {
+        U128(self.vault_assets)
+    }
+
+    pub fn get_total_shares(&self) -> U128 {
+        U128(self.total_shares)
+    }
+
+    pub fn get_investor_shares(&self, account_id: AccountId) -> U128 {
+        U128(self.investors.get(&account_id).unwrap_or(0))
+    }
+
+    /// What `account_id`'s shares would redeem for right now.
+    pub fn get_investor_assets(&self, account_id: AccountId) -> U128 {
+        self.preview_redeem(self.get_investor_shares(account_id))
+    }
+
+    pub fn convert_to_shares(&self, assets: U128) -> U128 {
+        U128(self.shares_for(assets.0, false))
+    }
+
+    pub fn convert_to_assets(&self, shares: U128) -> U128 {
+        U128(self.assets_for(shares.0, false))
+    }
+
+    /// Shares minted for investing `assets`.
+    pub fn preview_deposit(&self, assets: U128) -> U128 {
+        U128(self.shares_for(assets.0, false))
+    }
+
+    /// Assets needed to mint `shares`.
+    pub fn preview_mint(&self, shares: U128) -> U128 {
+        U128(self.assets_for(shares.0, true))
+    }
+
+    /// Shares burned to withdraw `assets`.
+    pub fn preview_withdraw(&self, assets: U128) -> U128 {
+        U128(self.shares_for(assets.0, true))
+    }
+
+    /// Assets paid out for redeeming `shares`.
+    pub fn preview_redeem(&self, shares: U128) -> U128 {
+        U128(self.assets_for(shares.0, false))
+    }
+}
+
+impl Contract {
+    pub(crate) fn shares_for(&self, assets: Balance, round_up: bool) -> Balance {
+        mul_div(
+            assets,
+            self.total_shares + 1,
+            self.vault_assets + 1,
+            round_up,
+        )
+    }
+
+    fn assets_for(&self, shares: Balance, round_up: bool) -> Balance {
+        mul_div(
+            shares,
+            self.vault_assets + 1,
+            self.total_shares + 1,
+            round_up,
+        )
+    }
+
+    /// Adds `assets` to the vault and gives the account the shares they buy.
+    pub(crate) fn mint_shares(&mut self, account_id: &AccountId, assets: Balance) -> Balance {
+        let shares = self.shares_for(assets, false);
+        assert!(shares > 0, "Deposit too small to mint shares");
+        self.register_share_holder(account_id);
+        self.credit(account_id, Ledger::Investor, shares);
+        self.total_shares += shares;
+        self.vault_assets += assets;
+        self.total_stake += assets;
+        FtMint {
+            owner_id: account_id,
+            amount: &U128(shares),
+            memo: None,
+        }
+        .emit();
+        shares
+    }
+
+    /// Takes `shares` from the account and `assets` out of the vault.
+    pub(crate) fn burn_shares(&mut self, account_id: &AccountId, shares: Balance, assets: Balance) {
+        self.debit(account_id, Ledger::Investor, shares);
+        self.total_shares -= shares;
+        self.vault_assets -= assets;
+        self.total_stake -= assets;
+        FtBurn {
+            owner_id: account_id,
+            amount: &U128(shares),
+            memo: None,
+        }
+        .emit();
+    }
+
+    /// Burns `shares` for what they are worth and unstakes the proceeds.
+    pub(crate) fn redeem_shares(&mut self, account_id: &AccountId, shares: Balance) -> Balance {
+        let assets = self.assets_for(shares, false);
+        assert!(assets > 0, "Redeemed shares are worth nothing");
+        self.burn_shares(account_id, shares, assets);
+        self.unstake_investor(account_id, assets);
+        assets
+    }
+
+    /// Adds yield to the vault, raising the price of every share.
+    pub(crate) fn accrue_investor_yield(&mut self, assets: Balance) {
+        self.vault_assets += assets;
+        self.total_stake += assets;
+    }
+
+    /// Queues assets already taken out of the vault for the account.
+    pub(crate) fn unstake_investor(&mut self, account_id: &AccountId, assets: Balance) {
+        self.queue_withdrawal(account_id, Ledger::Investor, assets);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn invest(contract: &mut Contract, name: &str, amount: Balance) {
+        set_context(name, amount);
+        contract.invest(U128(amount));
+    }
+
+    /// Alice holds 100 NEAR worth of shares that have earned 20% so far.
+    fn setup_vault() -> Contract {
+        let mut contract = setup();
+        invest(&mut contract, "alice.testnet", 100 * MIN_DEPOSIT);
+        contract.accrue_investor_yield(20 * MIN_DEPOSIT);
+        contract
+    }
+
+    #[test]
+    fn test_first_deposit_mints_one_share_per_yocto() {
+        let mut contract = setup();
+        invest(&mut contract, "alice.testnet", 100 * MIN_DEPOSIT);
+        assert_eq!(
+            contract.get_investor_shares(account("alice.testnet")),
+            U128(100 * MIN_DEPOSIT)
+        );
+        assert_eq!(contract.total_assets(), U128(100 * MIN_DEPOSIT));
+    }
+
+    #[test]
+    fn test_yield_raises_share_price() {
+        let contract = setup_vault();
+        assert_eq!(
+            contract.get_investor_assets(account("alice.testnet")),
+            U128(120 * MIN_DEPOSIT - 1)
+        );
+        assert_eq!(contract.total_stake, 120 * MIN_DEPOSIT);
+    }
+
+    #[test]
+    fn test_later_investor_buys_in_at_current_price() {
+        let mut contract = setup_vault();
+        invest(&mut contract, "bob.testnet", 60 * MIN_DEPOSIT);
+
+        let bob_shares = contract.get_investor_shares(account("bob.testnet")).0;
+        assert!(bob_shares <= 50 * MIN_DEPOSIT && bob_shares > 50 * MIN_DEPOSIT - 10);
+        // Bob's deposit leaves Alice's position where it was.
+        assert!(contract.get_investor_assets(account("alice.testnet")).0 >= 120 * MIN_DEPOSIT - 1);
+        assert!(contract.get_investor_assets(account("bob.testnet")).0 <= 60 * MIN_DEPOSIT);
+    }
+
+    #[test]
+    fn test_rounding_favours_the_vault() {
+        let mut contract = setup();
+        invest(&mut contract, "alice.testnet", 3 * MIN_DEPOSIT);
+        contract.accrue_investor_yield(MIN_DEPOSIT);
+
+        for assets in [1, 7, MIN_DEPOSIT + 1] {
+            let shares = contract.preview_deposit(U128(assets)).0;
+            assert!(contract.preview_redeem(U128(shares)).0 <= assets);
+            let burned = contract.preview_withdraw(U128(assets)).0;
+            assert!(burned >= shares);
+            assert!(contract.preview_mint(U128(shares)).0 <= assets);
+        }
+        assert_eq!(contract.preview_deposit(U128(1)), U128(0));
+        assert_eq!(contract.preview_withdraw(U128(1)), U128(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Deposit too small to mint shares")]
+    fn test_deposit_worth_less_than_a_share_is_rejected() {
+        let mut contract = setup();
+        invest(&mut contract, "alice.testnet", 1);
+        // One share is now worth about 50 NEAR.
+        contract.accrue_investor_yield(100 * MIN_DEPOSIT);
+        invest(&mut contract, "bob.testnet", MIN_DEPOSIT / 2);
+    }
+
+    #[test]
+    fn test_redeem_unstakes_what_shares_are_worth() {
+        let mut contract = setup_vault();
+        set_context("alice.testnet", 0);
+        let shares = 50 * MIN_DEPOSIT;
+        let expected = contract.preview_redeem(U128(shares));
+        assert_eq!(contract.redeem(U128(shares)), expected);
+
+        assert_eq!(
+            contract.get_investor_shares(account("alice.testnet")),
+            U128(50 * MIN_DEPOSIT)
+        );
+        assert_eq!(contract.total_unstaking, expected.0);
+        assert_eq!(contract.total_stake, contract.vault_assets);
+        let pending = contract.get_pending_withdrawals(account("alice.testnet"));
+        assert_eq!(pending[0].amount, expected);
+    }
+
+    #[test]
+    fn test_withdraw_burns_shares_rounded_up() {
+        let mut contract = setup_vault();
+        set_context("alice.testnet", 0);
+        let burned = contract.preview_withdraw(U128(60 * MIN_DEPOSIT)).0;
+        contract.withdraw(Ledger::Investor, U128(60 * MIN_DEPOSIT));
+        assert_eq!(
+            contract.get_investor_shares(account("alice.testnet")),
+            U128(100 * MIN_DEPOSIT - burned)
+        );
+        assert_eq!(contract.total_assets(), U128(60 * MIN_DEPOSIT));
+    }
+
+    #[test]
+    fn test_withdraw_all_redeems_every_share() {
+        let mut contract = setup_vault();
+        set_context("alice.testnet", 0);
+        contract.withdraw_all(Ledger::Investor);
+        assert_eq!(contract.get_total_shares(), U128(0));
+        // The virtual asset keeps the last yocto of rounding in the vault.
+        assert_eq!(contract.total_assets(), U128(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Withdrawal exceeds balance")]
+    fn test_cannot_redeem_more_shares_than_held() {
+        let mut contract = setup_vault();
+        set_context("alice.testnet", 0);
+        contract.redeem(U128(100 * MIN_DEPOSIT + 1));
+    }
+
+    #[test]
+    fn test_conversions_do_not_overflow() {
+        let mut contract = setup();
+        contract.total_shares = u128::MAX / 2;
+        contract.vault_assets = u128::MAX / 3;
+        let assets = u128::MAX / 4;
+        let shares = contract.convert_to_shares(U128(assets)).0;
+        assert!(contract.convert_to_assets(U128(shares)).0 <= assets);
+    }
+}
@@ -0,0 +1,616 @@
+//! The withdrawal queue. Money leaving the staking pool is requested first:
+//! requests made during an epoch join that epoch's batch, and a keeper's
+//! `sync_stake` settles every batch that is due at once. Batches it can pay
+//! out of pending deposits are available straight away; the rest are
+//! unstaked, and the pool holds them for `UNSTAKE_DELAY_EPOCHS`. Each
+//! account then collects its share with `complete_withdrawal`.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId, Balance};
+
+use crate::{Contract, ContractExt, Ledger, UNSTAKE_DELAY_EPOCHS};
+
+/// Withdrawals unstaked together, keyed by the epoch they are due in.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WithdrawalBatch {
+    pub amount: U128,
+    /// Part of the batch deposits cover, held by the contract for this
+    /// batch's requests alone until they are completed.
+    pub reserved: U128,
+    /// Epoch `sync_stake` settled the batch in.
+    pub unstaked_epoch: Option<u64>,
+    /// Paid out of deposits instead of unstaked, so there is no delay.
+    pub netted: bool,
+}
+
+/// Part of an account's withdrawal waiting in the batch for `epoch`.
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, PartialEq)]
+pub struct WithdrawalRequest {
+    pub epoch: u64,
+    /// The balance the funds came out of, which a failed payout goes back
+    /// to; prizes count as the winner's player balance.
+    pub ledger: Ledger,
+    pub amount: Balance,
+    /// Already out of the unstake delay, as when a completed withdrawal is
+    /// put back after the pool or the transfer failed.
+    pub ready: bool,
+    /// Part of a ready request the contract already holds, as when the
+    /// transfer bounced. The rest is still in the staking pools.
+    pub held: Balance,
+}
+
+/// A withdrawal as the account sees it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingWithdrawal {
+    pub amount: U128,
+    /// When the funds can be collected; an estimate until the batch is
+    /// unstaked, assuming that happens in the epoch it is due.
+    pub available_epoch: u64,
+    /// Whether `sync_stake` has settled the batch.
+    pub unstaked: bool,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Queues `amount` of the caller's balance in `ledger` for the next
+    /// unstake. The same as `withdraw`.
+    pub fn request_withdrawal(&mut self, ledger: Ledger, amount: U128) {
+        self.withdraw(ledger, amount);
+    }
+
+    /// Sends the caller every withdrawal of theirs that is available, out
+    /// of what the contract holds for each request first and then out of
+    /// the staking pools.
+    pub fn complete_withdrawal(&mut self) {
+        let account_id = env::predecessor_account_id();
+        let requests = self
+            .withdrawal_requests
+            .get(&account_id)
+            .expect("No pending withdrawal");
+        let (ready, waiting): (Vec<_>, Vec<_>) = requests
+            .into_iter()
+            .partition(|request| self.request_available(request));
+        if ready.is_empty() {
+            let next = waiting
+                .iter()
+                .map(|request| self.pending_withdrawal(request).available_epoch)
+                .min()
+                .unwrap();
+            panic!("Withdrawal is not available until epoch {}", next);
+        }
+        self.set_withdrawal_requests(&account_id, waiting);
+
+        for ledger in [Ledger::Investor, Ledger::Player] {
+            let mut amount = 0;
+            let mut reserved = 0;
+            for request in ready.iter().filter(|request| request.ledger == ledger) {
+                amount += request.amount;
+                reserved += self.take_reserved(request);
+            }
+            if reserved > 0 {
+                self.withdrawal_reserve -= reserved;
+                self.total_unstaking -= reserved;
+                self.transfer_to(account_id.clone(), ledger, reserved);
+            }
+            if amount > reserved {
+                self.withdraw_unstaked(account_id.clone(), ledger, amount - reserved);
+            }
+        }
+    }
+
+    /// The caller's withdrawals in request order, with when each can be
+    /// collected.
+    pub fn get_pending_withdrawals(&self, account_id: AccountId) -> Vec<PendingWithdrawal> {
+        self.withdrawal_requests
+            .get(&account_id)
+            .unwrap_or_default()
+            .iter()
+            .map(|request| self.pending_withdrawal(request))
+            .collect()
+    }
+
+    pub fn get_withdrawal_batch(&self, epoch: u64) -> Option<WithdrawalBatch> {
+        self.withdrawal_batches.get(&epoch)
+    }
+
+    /// Epochs whose batches are still waiting for `sync_stake`.
+    pub fn get_unstake_queue(&self) -> Vec<u64> {
+        self.unstake_queue.to_vec()
+    }
+}
+
+impl Contract {
+    /// Adds `amount`, already taken off the account's balance in `ledger`
+    /// and out of `total_stake` or the prize pool, to the batch being
+    /// filled.
+    pub(crate) fn queue_withdrawal(
+        &mut self,
+        account_id: &AccountId,
+        ledger: Ledger,
+        amount: Balance,
+    ) {
+        let mut epoch = env::epoch_height();
+        let mut batch = self.withdrawal_batches.get(&epoch);
+        // This epoch's batch has already gone out; join the next one.
+        if matches!(
+            batch,
+            Some(WithdrawalBatch {
+                unstaked_epoch: Some(_),
+                ..
+            })
+        ) {
+            epoch += 1;
+            batch = self.withdrawal_batches.get(&epoch);
+        }
+        let mut batch = batch.unwrap_or_else(|| {
+            self.unstake_queue.push(&epoch);
+            WithdrawalBatch {
+                amount: U128(0),
+                reserved: U128(0),
+                unstaked_epoch: None,
+                netted: false,
+            }
+        });
+        batch.amount.0 += amount;
+        self.withdrawal_batches.insert(&epoch, &batch);
+        self.total_unstaking += amount;
+        self.add_withdrawal_request(
+            account_id,
+            WithdrawalRequest {
+                epoch,
+                ledger,
+                amount,
+                ready: false,
+                held: 0,
+            },
+        );
+    }
+
+    /// Queued batches due by now and the part of them deposits do not
+    /// cover yet.
+    pub(crate) fn due_batches(&self) -> (Vec<u64>, Balance) {
+        let epoch = env::epoch_height();
+        let mut due = vec![];
+        let mut amount = 0;
+        for batch_epoch in self.unstake_queue.iter().filter(|e| *e <= epoch) {
+            let batch = self.withdrawal_batches.get(&batch_epoch).unwrap();
+            amount += batch.amount.0 - batch.reserved.0;
+            due.push(batch_epoch);
+        }
+        (due, amount)
+    }
+
+    /// Sets `amount` of deposits aside for `epochs`' batches, oldest first,
+    /// as far as each still needs it, and returns what is left over.
+    pub(crate) fn reserve_batches(&mut self, epochs: &[u64], amount: Balance) -> Balance {
+        let mut remaining = amount;
+        for batch_epoch in epochs {
+            let mut batch = self.withdrawal_batches.get(batch_epoch).unwrap();
+            let reserved = std::cmp::min(remaining, batch.amount.0 - batch.reserved.0);
+            batch.reserved.0 += reserved;
+            remaining -= reserved;
+            self.withdrawal_batches.insert(batch_epoch, &batch);
+        }
+        self.withdrawal_reserve += amount - remaining;
+        remaining
+    }
+
+    /// Whether deposits cover all of a batch.
+    pub(crate) fn batch_reserved(&self, epoch: u64) -> bool {
+        let batch = self.withdrawal_batches.get(&epoch).unwrap();
+        batch.reserved == batch.amount
+    }
+
+    /// Takes settled batches out of the queue. A batch deposits fully cover
+    /// is available at once.
+    pub(crate) fn close_batches(&mut self, epochs: &[u64]) {
+        let epoch = env::epoch_height();
+        for batch_epoch in epochs {
+            let mut batch = self.withdrawal_batches.get(batch_epoch).unwrap();
+            batch.unstaked_epoch = Some(epoch);
+            batch.netted = batch.reserved == batch.amount;
+            self.withdrawal_batches.insert(batch_epoch, &batch);
+        }
+        let queued: Vec<u64> = self
+            .unstake_queue
+            .iter()
+            .filter(|e| !epochs.contains(e))
+            .collect();
+        self.unstake_queue.clear();
+        self.unstake_queue.extend(queued);
+    }
+
+    /// Reopens the batches a failed unstake was for. They are stamped
+    /// again by the sync that retries it, so none of them reports an
+    /// availability epoch for funds that were never unstaked.
+    pub(crate) fn reopen_batches(&mut self, epochs: &[u64]) {
+        for batch_epoch in epochs {
+            let mut batch = self.withdrawal_batches.get(batch_epoch).unwrap();
+            if batch.netted || batch.unstaked_epoch.is_none() {
+                continue;
+            }
+            batch.unstaked_epoch = None;
+            self.withdrawal_batches.insert(batch_epoch, &batch);
+            self.shortfall_batches.push(*batch_epoch);
+        }
+    }
+
+    /// Gives an account back a withdrawal that had already cleared the
+    /// unstake delay, so it can be completed again straight away. `held`
+    /// of it is already back in the contract.
+    pub(crate) fn restore_withdrawal(
+        &mut self,
+        account_id: &AccountId,
+        ledger: Ledger,
+        amount: Balance,
+        held: Balance,
+    ) {
+        self.add_withdrawal_request(
+            account_id,
+            WithdrawalRequest {
+                epoch: env::epoch_height(),
+                ledger,
+                amount,
+                ready: true,
+                held,
+            },
+        );
+    }
+
+    /// Takes what the contract holds for `request` out of its batch, or
+    /// out of the request itself once it is ready.
+    fn take_reserved(&mut self, request: &WithdrawalRequest) -> Balance {
+        if request.ready {
+            return request.held;
+        }
+        let mut batch = self.withdrawal_batches.get(&request.epoch).unwrap();
+        let reserved = std::cmp::min(request.amount, batch.reserved.0);
+        batch.reserved.0 -= reserved;
+        self.withdrawal_batches.insert(&request.epoch, &batch);
+        reserved
+    }
+
+    fn add_withdrawal_request(&mut self, account_id: &AccountId, request: WithdrawalRequest) {
+        let mut requests = self.withdrawal_requests.get(account_id).unwrap_or_default();
+        match requests.iter_mut().find(|r| {
+            r.epoch == request.epoch && r.ledger == request.ledger && r.ready == request.ready
+        }) {
+            Some(existing) => {
+                existing.amount += request.amount;
+                existing.held += request.held;
+            }
+            None => requests.push(request),
+        }
+        self.withdrawal_requests.insert(account_id, &requests);
+    }
+
+    fn set_withdrawal_requests(
+        &mut self,
+        account_id: &AccountId,
+        requests: Vec<WithdrawalRequest>,
+    ) {
+        if requests.is_empty() {
+            self.withdrawal_requests.remove(account_id);
+        } else {
+            self.withdrawal_requests.insert(account_id, &requests);
+        }
+    }
+
+    fn pending_withdrawal(&self, request: &WithdrawalRequest) -> PendingWithdrawal {
+        if request.ready {
+            return PendingWithdrawal {
+                amount: U128(request.amount),
+                available_epoch: request.epoch,
+                unstaked: true,
+            };
+        }
+        let batch = self.withdrawal_batches.get(&request.epoch);
+        let available_epoch = match batch {
+            Some(WithdrawalBatch {
+                unstaked_epoch: Some(epoch),
+                netted: true,
+                ..
+            }) => epoch,
+            Some(WithdrawalBatch {
+                unstaked_epoch: Some(epoch),
+                ..
+            }) => std::cmp::max(epoch + UNSTAKE_DELAY_EPOCHS, self.unstaked_unlock_epoch()),
+            _ => std::cmp::max(request.epoch, env::epoch_height()) + UNSTAKE_DELAY_EPOCHS,
+        };
+        PendingWithdrawal {
+            amount: U128(request.amount),
+            available_epoch,
+            unstaked: matches!(
+                batch,
+                Some(WithdrawalBatch {
+                    unstaked_epoch: Some(_),
+                    ..
+                })
+            ),
+        }
+    }
+
+    fn request_available(&self, request: &WithdrawalRequest) -> bool {
+        let pending = self.pending_withdrawal(request);
+        pending.unstaked && env::epoch_height() >= pending.available_epoch
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_sdk::PromiseResult;
+
+    fn invest(contract: &mut Contract, name: &str, amount: Balance) {
+        set_context(name, amount);
+        contract.invest(U128(amount));
+    }
+
+    /// Alice and Bob each hold 10 NEAR of staked shares and both withdraw
+    /// part of it in epoch 5.
+    fn setup_requests() -> Contract {
+        let mut contract = setup();
+        invest(&mut contract, "alice.testnet", 10 * MIN_DEPOSIT);
+        invest(&mut contract, "bob.testnet", 10 * MIN_DEPOSIT);
+        contract.sync_stake();
+        set_context_at("alice.testnet", 0, 0, 5);
+        contract.request_withdrawal(Ledger::Investor, U128(2 * MIN_DEPOSIT));
+        set_context_at("bob.testnet", 0, 0, 5);
+        contract.request_withdrawal(Ledger::Investor, U128(3 * MIN_DEPOSIT));
+        contract
+    }
+
+    #[test]
+    fn test_requests_in_an_epoch_share_one_unstake() {
+        let mut contract = setup_requests();
+        assert_eq!(
+            contract.get_withdrawal_batch(5),
+            Some(WithdrawalBatch {
+                amount: U128(5 * MIN_DEPOSIT),
+                reserved: U128(0),
+                unstaked_epoch: None,
+                netted: false,
+            })
+        );
+        assert_eq!(contract.total_unstaking, 5 * MIN_DEPOSIT);
+
+        set_context_at("keeper.testnet", 0, 0, 5);
+        contract.sync_stake();
+        let calls = function_calls();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].1, "unstake");
+        assert_eq!(calls[1].1, "on_sync_unstake");
+        assert!(contract.get_unstake_queue().is_empty());
+        assert_eq!(
+            contract.get_withdrawal_batch(5).unwrap().unstaked_epoch,
+            Some(5)
+        );
+    }
+
+    #[test]
+    fn test_request_after_unstake_joins_next_batch() {
+        let mut contract = setup_requests();
+        set_context_at("keeper.testnet", 0, 0, 5);
+        contract.sync_stake();
+
+        set_context_at("alice.testnet", 0, 0, 5);
+        contract.request_withdrawal(Ledger::Investor, U128(MIN_DEPOSIT));
+        assert_eq!(contract.get_unstake_queue(), vec![6]);
+        assert_eq!(
+            contract.get_pending_withdrawals(account("alice.testnet")),
+            vec![
+                PendingWithdrawal {
+                    amount: U128(2 * MIN_DEPOSIT),
+                    available_epoch: 5 + UNSTAKE_DELAY_EPOCHS,
+                    unstaked: true,
+                },
+                PendingWithdrawal {
+                    amount: U128(MIN_DEPOSIT),
+                    available_epoch: 6 + UNSTAKE_DELAY_EPOCHS,
+                    unstaked: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Stake already synced in epoch 5")]
+    fn test_next_batch_waits_for_next_sync() {
+        let mut contract = setup_requests();
+        set_context_at("keeper.testnet", 0, 0, 5);
+        contract.sync_stake();
+        set_context_at("alice.testnet", 0, 0, 5);
+        contract.request_withdrawal(Ledger::Investor, U128(MIN_DEPOSIT));
+
+        set_context_at("keeper.testnet", 0, 0, 5);
+        contract.sync_stake();
+    }
+
+    #[test]
+    fn test_late_unstake_pushes_availability_back() {
+        let mut contract = setup_requests();
+        set_context_at("keeper.testnet", 0, 0, 7);
+        assert_eq!(
+            contract.get_pending_withdrawals(account("bob.testnet"))[0].available_epoch,
+            7 + UNSTAKE_DELAY_EPOCHS
+        );
+        contract.sync_stake();
+        assert_eq!(
+            contract.get_pending_withdrawals(account("bob.testnet"))[0].available_epoch,
+            7 + UNSTAKE_DELAY_EPOCHS
+        );
+    }
+
+    #[test]
+    fn test_failed_batch_unstake_is_retried() {
+        let mut contract = setup_requests();
+        set_context_at("keeper.testnet", 0, 0, 5);
+        contract.sync_stake();
+
+        set_callback_context(vec![PromiseResult::Failed]);
+        assert!(!contract.on_sync_unstake(
+            account("staking.testnet"),
+            U128(5 * MIN_DEPOSIT),
+            U128(0),
+            vec![5]
+        ));
+        assert!(contract.get_unstake_queue().is_empty());
+        assert_eq!(contract.total_unstaking, 5 * MIN_DEPOSIT);
+        set_context_at("keeper.testnet", 0, 0, 6);
+        let sync = contract.get_stake_sync();
+        assert_eq!(sync.pending_unstake, U128(5 * MIN_DEPOSIT));
+        assert_eq!(sync.last_sync_epoch, None);
+        assert_eq!(
+            contract.get_withdrawal_batch(5).unwrap().unstaked_epoch,
+            None
+        );
+        assert_eq!(
+            contract.get_pending_withdrawals(account("alice.testnet"))[0],
+            PendingWithdrawal {
+                amount: U128(2 * MIN_DEPOSIT),
+                available_epoch: 6 + UNSTAKE_DELAY_EPOCHS,
+                unstaked: false,
+            }
+        );
+
+        contract.sync_stake();
+        assert_eq!(
+            contract.get_withdrawal_batch(5).unwrap().unstaked_epoch,
+            Some(6)
+        );
+        assert!(contract.shortfall_batches.is_empty());
+    }
+
+    #[test]
+    fn test_reserve_is_held_for_its_own_batch() {
+        let mut contract = setup_requests();
+        set_context_at("keeper.testnet", 0, 0, 5);
+        contract.sync_stake();
+        set_context_at("bob.testnet", 0, 0, 6);
+        contract.request_withdrawal(Ledger::Investor, U128(MIN_DEPOSIT));
+        set_context_at("carol.testnet", MIN_DEPOSIT, 0, 6);
+        contract.invest(U128(MIN_DEPOSIT));
+        contract.sync_stake();
+        assert_eq!(
+            contract.get_withdrawal_batch(6),
+            Some(WithdrawalBatch {
+                amount: U128(MIN_DEPOSIT),
+                reserved: U128(MIN_DEPOSIT),
+                unstaked_epoch: Some(6),
+                netted: true,
+            })
+        );
+
+        // Alice's batch was unstaked, so none of bob's reserve is hers.
+        set_context_at("alice.testnet", 0, 0, 5 + UNSTAKE_DELAY_EPOCHS);
+        contract.complete_withdrawal();
+        assert_eq!(contract.withdrawal_reserve, MIN_DEPOSIT);
+        assert_eq!(
+            function_call_args("on_unstaked_balance_available")["amount"],
+            "2000000"
+        );
+
+        set_context_at("bob.testnet", 0, 0, 5 + UNSTAKE_DELAY_EPOCHS);
+        contract.complete_withdrawal();
+        assert_eq!(contract.withdrawal_reserve, 0);
+        assert_eq!(contract.get_withdrawal_batch(6).unwrap().reserved, U128(0));
+        assert_eq!(
+            function_call_args("on_unstaked_balance_available")["amount"],
+            "3000000"
+        );
+    }
+
+    #[test]
+    fn test_complete_withdrawal_collects_every_available_request() {
+        let mut contract = setup_requests();
+        set_context_at("keeper.testnet", 0, 0, 5);
+        contract.sync_stake();
+        set_context_at("alice.testnet", 0, 0, 6);
+        contract.request_withdrawal(Ledger::Investor, U128(MIN_DEPOSIT));
+        set_callback_context(vec![PromiseResult::Failed]);
+        contract.on_pool_withdraw(
+            account("alice.testnet"),
+            Ledger::Investor,
+            account("staking.testnet"),
+            U128(MIN_DEPOSIT),
+        );
+
+        set_context_at("alice.testnet", 0, 0, 5 + UNSTAKE_DELAY_EPOCHS);
+        contract.complete_withdrawal();
+        assert_eq!(
+            contract.get_pending_withdrawals(account("alice.testnet")),
+            vec![PendingWithdrawal {
+                amount: U128(MIN_DEPOSIT),
+                available_epoch: 9 + UNSTAKE_DELAY_EPOCHS,
+                unstaked: false,
+            }]
+        );
+        let calls = function_calls();
+        assert_eq!(calls[1].1, "on_unstaked_balance_available");
+    }
+
+    #[test]
+    fn test_failed_player_payout_returns_to_player_balance() {
+        let mut contract = setup();
+        set_context("bob.testnet", 10 * MIN_DEPOSIT);
+        contract.play(U128(10 * MIN_DEPOSIT));
+        contract.sync_stake();
+        set_context_at("bob.testnet", 0, 0, 1);
+        contract.withdraw(Ledger::Player, U128(2 * MIN_DEPOSIT));
+        contract.sync_stake();
+
+        set_context_at("bob.testnet", 0, 0, 1 + UNSTAKE_DELAY_EPOCHS);
+        contract.complete_withdrawal();
+        assert_eq!(
+            function_call_args("on_unstaked_balance_available")["ledger"],
+            "player"
+        );
+
+        set_callback_context(vec![PromiseResult::Successful(vec![])]);
+        contract.on_pool_withdraw(
+            account("bob.testnet"),
+            Ledger::Player,
+            account("staking.testnet"),
+            U128(2 * MIN_DEPOSIT),
+        );
+        assert_eq!(
+            function_call_args("on_withdraw_transfer")["ledger"],
+            "player"
+        );
+        set_callback_context(vec![PromiseResult::Failed]);
+        contract.on_withdraw_transfer(
+            account("bob.testnet"),
+            Ledger::Player,
+            U128(2 * MIN_DEPOSIT),
+        );
+        assert_eq!(
+            contract.players.get(&account("bob.testnet")),
+            Some(10 * MIN_DEPOSIT)
+        );
+        assert_eq!(contract.liquid_buffer, 3 * MIN_DEPOSIT);
+        assert_eq!(contract.total_unstaking, 0);
+        assert!(contract
+            .get_pending_withdrawals(account("bob.testnet"))
+            .is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Withdrawal is not available until epoch 9")]
+    fn test_complete_withdrawal_needs_unstaked_batch() {
+        let mut contract = setup_requests();
+        set_context_at("alice.testnet", 0, 0, 5);
+        contract.complete_withdrawal();
+    }
+
+    #[test]
+    #[should_panic(expected = "No pending withdrawal")]
+    fn test_complete_withdrawal_requires_request() {
+        let mut contract = setup();
+        set_context("alice.testnet", 0);
+        contract.complete_withdrawal();
+    }
+}
@@ -0,0 +1,195 @@
+//! Token deposits against a locally deployed mock-ft. Needs a near-sandbox
+//! binary in NEAR_SANDBOX_BIN_PATH and both contracts built first:
+//!
+//!     cargo build --release --target wasm32-unknown-unknown -p smart-contracts -p mock-ft
+//!     cargo test --test ft_deposits -- --ignored
+use near_sdk::json_types::U128;
+use near_workspaces::types::NearToken;
+use near_workspaces::{Account, Contract};
+use serde_json::json;
+
+const ONE_TOKEN: u128 = 1_000_000_000_000_000_000_000_000;
+
+fn wasm(name: &str) -> anyhow::Result<Vec<u8>> {
+    let path = format!(
+        "{}/target/wasm32-unknown-unknown/release/{}.wasm",
+        env!("CARGO_MANIFEST_DIR"),
+        name
+    );
+    Ok(std::fs::read(path)?)
+}
+
+async fn ft_balance_of(token: &Contract, account: &Account) -> anyhow::Result<u128> {
+    let balance: U128 = token
+        .view("ft_balance_of")
+        .args_json(json!({ "account_id": account.id() }))
+        .await?
+        .json()?;
+    Ok(balance.0)
+}
+
+async fn token_balance(
+    lottery: &Contract,
+    token: &Contract,
+    ledger: &str,
+    account: &Account,
+) -> anyhow::Result<u128> {
+    let balance: U128 = lottery
+        .view("get_token_balance")
+        .args_json(json!({
+            "token_id": token.id(),
+            "ledger": ledger,
+            "account_id": account.id(),
+        }))
+        .await?
+        .json()?;
+    Ok(balance.0)
+}
+
+async fn storage_deposit(token: &Contract, account: &Account) -> anyhow::Result<()> {
+    account
+        .call(token.id(), "storage_deposit")
+        .args_json(json!({ "account_id": account.id() }))
+        .deposit(NearToken::from_millinear(10))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+async fn ft_transfer_call(
+    token: &Contract,
+    from: &Account,
+    to: &Contract,
+    amount: u128,
+    msg: &str,
+) -> anyhow::Result<()> {
+    from.call(token.id(), "ft_transfer_call")
+        .args_json(json!({
+            "receiver_id": to.id(),
+            "amount": U128(amount),
+            "msg": msg,
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+async fn withdraw_token(
+    lottery: &Contract,
+    token: &Contract,
+    account: &Account,
+    ledger: &str,
+    amount: u128,
+) -> anyhow::Result<bool> {
+    Ok(account
+        .call(lottery.id(), "withdraw_token")
+        .args_json(json!({
+            "token_id": token.id(),
+            "ledger": ledger,
+            "amount": U128(amount),
+        }))
+        .max_gas()
+        .transact()
+        .await?
+        .json()?)
+}
+
+#[tokio::test]
+#[ignore = "needs near-sandbox and the wasm builds, see the module docs"]
+async fn test_token_deposits_against_a_deployed_token() -> anyhow::Result<()> {
+    let worker = near_workspaces::sandbox().await?;
+    let token = worker.dev_deploy(&wasm("mock_ft")?).await?;
+    let lottery = worker.dev_deploy(&wasm("smart_contracts")?).await?;
+    token
+        .call("new")
+        .args_json(json!({ "symbol": "wNEAR" }))
+        .transact()
+        .await?
+        .into_result()?;
+    lottery
+        .call("new")
+        .args_json(json!({
+            "owner_id": lottery.id(),
+            "staking_contract": "staking.test.near",
+            "min_deposit": U128(ONE_TOKEN),
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    lottery
+        .call("add_accepted_token")
+        .args_json(json!({ "token_id": token.id() }))
+        .transact()
+        .await?
+        .into_result()?;
+    storage_deposit(&token, lottery.as_account()).await?;
+
+    let alice = worker.dev_create_account().await?;
+    alice
+        .call(token.id(), "mint")
+        .args_json(json!({ "amount": U128(5 * ONE_TOKEN) }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    ft_transfer_call(&token, &alice, &lottery, 2 * ONE_TOKEN, "invest").await?;
+    ft_transfer_call(&token, &alice, &lottery, ONE_TOKEN, "play").await?;
+    assert_eq!(
+        token_balance(&lottery, &token, "investor", &alice).await?,
+        2 * ONE_TOKEN
+    );
+    assert_eq!(
+        token_balance(&lottery, &token, "player", &alice).await?,
+        ONE_TOKEN
+    );
+    assert_eq!(
+        ft_balance_of(&token, lottery.as_account()).await?,
+        3 * ONE_TOKEN
+    );
+
+    // A msg the lottery does not know makes it refuse the deposit, and the
+    // token refunds it.
+    ft_transfer_call(&token, &alice, &lottery, ONE_TOKEN, "stake").await?;
+    assert_eq!(ft_balance_of(&token, &alice).await?, 2 * ONE_TOKEN);
+    assert_eq!(
+        ft_balance_of(&token, lottery.as_account()).await?,
+        3 * ONE_TOKEN
+    );
+
+    assert!(withdraw_token(&lottery, &token, &alice, "player", ONE_TOKEN).await?);
+    assert_eq!(ft_balance_of(&token, &alice).await?, 3 * ONE_TOKEN);
+    assert_eq!(token_balance(&lottery, &token, "player", &alice).await?, 0);
+
+    // Once alice is no longer registered with the token the transfer
+    // fails, and her deposit is kept for her.
+    let bob = worker.dev_create_account().await?;
+    storage_deposit(&token, &bob).await?;
+    alice
+        .call(token.id(), "ft_transfer")
+        .args_json(json!({ "receiver_id": bob.id(), "amount": U128(3 * ONE_TOKEN) }))
+        .deposit(NearToken::from_yoctonear(1))
+        .transact()
+        .await?
+        .into_result()?;
+    alice
+        .call(token.id(), "storage_unregister")
+        .args_json(json!({}))
+        .deposit(NearToken::from_yoctonear(1))
+        .transact()
+        .await?
+        .into_result()?;
+    assert!(!withdraw_token(&lottery, &token, &alice, "investor", 2 * ONE_TOKEN).await?);
+    assert_eq!(
+        token_balance(&lottery, &token, "investor", &alice).await?,
+        2 * ONE_TOKEN
+    );
+    assert_eq!(
+        ft_balance_of(&token, lottery.as_account()).await?,
+        2 * ONE_TOKEN
+    );
+    Ok(())
+}